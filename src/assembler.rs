@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::vm::*;
+
+// Textual assembly for the VM, one instruction per line
+//
+//     .string "hello\n"       // constants[0]
+//     .float 2.5              // constants[1]
+//     main:
+//         move a 5
+//         jeq 1 1 done        // jump targets may be labels or addresses
+//         call print
+//     done:
+//         ret
+//
+// Jump targets are resolved here, call and la targets are left to VM::load.
+
+#[derive(Debug)]
+pub enum AssemblerError {
+    Syntax { line: usize, message: String },
+    Link(Vec<LinkError>),
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::Syntax { line, message } => write!(f, "line {line}: {message}"),
+            AssemblerError::Link(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join(", "))
+            }
+        }
+    }
+}
+
+struct Line<'a> {
+    number: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+pub fn assemble(source: &str) -> Result<VM, AssemblerError> {
+    let mut constants = ConstantPool::new();
+    let mut labels: Vec<(String, Address)> = vec![];
+    let mut lines: Vec<Line> = vec![];
+
+    // First pass, collect labels and constants so jumps can refer forward
+    for (i, text) in source.lines().enumerate() {
+        let number = i + 1;
+        let mut text = strip_comment(text).trim();
+
+        if let Some(rest) = text.strip_prefix(".string") {
+            let string = parse_string(rest.trim()).ok_or_else(|| syntax(number, "Expected string literal"))?;
+            constants.push(Constant::String(string));
+            continue;
+        }
+        if let Some(rest) = text.strip_prefix(".float") {
+            let float = rest.trim().parse::<f64>().map_err(|_| syntax(number, "Expected float literal"))?;
+            constants.push(Constant::Float(float));
+            continue;
+        }
+
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                return Err(syntax(number, &format!("Invalid label {label}")));
+            }
+            labels.push((label.to_string(), lines.len() as Address));
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let mut words = text.split_whitespace();
+        let mnemonic = words.next().unwrap();
+        lines.push(Line { number, mnemonic, operands: words.collect() });
+    }
+
+    let addresses: HashMap<&str, Address> = labels
+        .iter()
+        .map(|(label, address)| (label.as_str(), *address))
+        .collect();
+
+    let mut instructions = vec![];
+    for line in &lines {
+        instructions.push(parse_instruction(line, &addresses)?);
+    }
+
+    let mut vm = VM::load(instructions, labels, constants).map_err(AssemblerError::Link)?;
+    vm.set_source_lines(lines.iter().map(|line| line.number).collect());
+    Ok(vm)
+}
+
+pub fn disassemble(vm: &VM) -> String {
+    let mut output = String::new();
+
+    for constant in vm.constants().iter() {
+        match constant {
+            Constant::String(string) => output += &format!(".string {}\n", quote(string)),
+            Constant::Float(float) => output += &format!(".float {float:?}\n"),
+        }
+    }
+
+    let mut labels: Vec<(&String, &Address)> = vm.labels().iter().collect();
+    labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+
+    let names = label_names(vm.labels());
+    let mut labels = labels.into_iter().peekable();
+    for (address, instruction) in vm.instructions().iter().enumerate() {
+        while let Some((label, _)) = labels.next_if(|(_, a)| **a as usize == address) {
+            output += &format!("{label}:\n");
+        }
+        output += &format!("    {}\n", format_instruction(instruction, &names));
+    }
+
+    for (label, _) in labels {
+        output += &format!("{label}:\n");
+    }
+
+    output
+}
+
+// The name printed for each labelled address, the first in alphabetical
+// order when several labels share one
+pub fn label_names(labels: &HashMap<String, Address>) -> HashMap<Address, String> {
+    let mut names: HashMap<Address, String> = HashMap::new();
+    for (label, address) in labels {
+        let name = names.entry(*address).or_insert_with(|| label.clone());
+        if label < name {
+            *name = label.clone();
+        }
+    }
+    names
+}
+
+pub fn format_instruction(instruction: &Instruction, names: &HashMap<Address, String>) -> String {
+    let address = |address: &Address| match names.get(address) {
+        Some(name) => name.clone(),
+        None => address.to_string(),
+    };
+    let target = |target: &Target| match target {
+        Target::Label(label) => label.clone(),
+        Target::Address(a) => address(a),
+    };
+
+    match instruction {
+        Instruction::Noop => "noop".to_string(),
+        Instruction::Ret => "ret".to_string(),
+        Instruction::Move(to, value) => format!("move {} {value}", register(to)),
+        Instruction::Copy(to, from) => format!("copy {} {}", register(to), register(from)),
+        Instruction::Add(to, from) => format!("add {} {}", register(to), register(from)),
+        Instruction::Sub(to, from) => format!("sub {} {}", register(to), register(from)),
+        Instruction::Mul(to, from) => format!("mul {} {}", register(to), register(from)),
+        Instruction::Div(to, from) => format!("div {} {}", register(to), register(from)),
+        Instruction::Not(r) => format!("not {}", register(r)),
+        Instruction::Jump(a) => format!("jump {}", address(a)),
+        Instruction::Call(t) => format!("call {}", target(t)),
+        Instruction::JEQ(a, b, to) => format!("jeq {a} {b} {}", address(to)),
+        Instruction::JGT(a, b, to) => format!("jgt {a} {b} {}", address(to)),
+        Instruction::JGE(a, b, to) => format!("jge {a} {b} {}", address(to)),
+        Instruction::LA(r, t) => format!("la {} {}", register(r), target(t)),
+        Instruction::LW(r, a) => format!("lw {} {a}", register(r)),
+        Instruction::SW(r, a) => format!("sw {} {a}", register(r)),
+        Instruction::LC(r, index) => format!("lc {} {index}", register(r)),
+        Instruction::LR(to, from) => format!("lr {} {}", register(to), register(from)),
+        Instruction::Push(value) => format!("push {value}"),
+        Instruction::PushR(r) => format!("pushr {}", register(r)),
+        Instruction::Pop(r) => format!("pop {}", register(r)),
+        Instruction::Enter(locals) => format!("enter {locals}"),
+        Instruction::Leave(args) => format!("leave {args}"),
+        Instruction::LF(r, offset) => format!("lf {} {offset}", register(r)),
+        Instruction::SF(r, offset) => format!("sf {} {offset}", register(r)),
+        Instruction::SysCall(syscall) => match syscall {
+            SysCall::Read { file_descriptor, buf_pointer, count } => {
+                format!("syscall read {file_descriptor} {buf_pointer} {count}")
+            }
+            SysCall::Write { file_descriptor, buf_pointer, count } => {
+                format!("syscall write {file_descriptor} {buf_pointer} {count}")
+            }
+            SysCall::WriteFloat { file_descriptor, buf_pointer } => {
+                format!("syscall writefloat {file_descriptor} {buf_pointer}")
+            }
+            SysCall::WriteInt { file_descriptor, register: r } => {
+                format!("syscall writeint {file_descriptor} {}", register(r))
+            }
+            SysCall::WriteBool { file_descriptor, register: r } => {
+                format!("syscall writebool {file_descriptor} {}", register(r))
+            }
+            SysCall::WriteString { file_descriptor, register: r } => {
+                format!("syscall writestring {file_descriptor} {}", register(r))
+            }
+            SysCall::WriteFloatR { file_descriptor, register: r } => {
+                format!("syscall writefloatr {file_descriptor} {}", register(r))
+            }
+        },
+    }
+}
+
+fn register(register: &Register) -> &'static str {
+    match register {
+        Register::A => "a",
+        Register::B => "b",
+        Register::C => "c",
+        Register::D => "d",
+    }
+}
+
+fn parse_instruction(line: &Line, labels: &HashMap<&str, Address>) -> Result<Instruction, AssemblerError> {
+    let number = line.number;
+    let operands = &line.operands;
+
+    let arity = match line.mnemonic {
+        "noop" | "ret" => 0,
+        "not" | "jump" | "call" | "push" | "pushr" | "pop" | "enter" | "leave" => 1,
+        "move" | "copy" | "add" | "sub" | "mul" | "div" | "la" | "lw" | "sw" | "lc" | "lr" | "lf" | "sf" => 2,
+        "jeq" | "jgt" | "jge" => 3,
+        "syscall" => operands.len().max(1),
+        mnemonic => return Err(syntax(number, &format!("Unknown instruction {mnemonic}"))),
+    };
+    if operands.len() != arity {
+        return Err(syntax(number, &format!("{} expects {arity} operands, got {}", line.mnemonic, operands.len())));
+    }
+
+    let register = |i: usize| -> Result<Register, AssemblerError> {
+        match operands[i].to_lowercase().as_str() {
+            "a" => Ok(Register::A),
+            "b" => Ok(Register::B),
+            "c" => Ok(Register::C),
+            "d" => Ok(Register::D),
+            operand => Err(syntax(number, &format!("Expected register, got {operand}"))),
+        }
+    };
+    let integer = |i: usize| -> Result<u32, AssemblerError> {
+        operands[i]
+            .parse::<u32>()
+            .map_err(|_| syntax(number, &format!("Expected integer, got {}", operands[i])))
+    };
+    let offset = |i: usize| -> Result<Offset, AssemblerError> {
+        operands[i]
+            .parse::<Offset>()
+            .map_err(|_| syntax(number, &format!("Expected offset, got {}", operands[i])))
+    };
+    let address = |i: usize| -> Result<Address, AssemblerError> {
+        if let Some(address) = labels.get(operands[i]) {
+            return Ok(*address);
+        }
+        operands[i]
+            .parse::<Address>()
+            .map_err(|_| syntax(number, &format!("Undefined label {}", operands[i])))
+    };
+    let target = |i: usize| -> Result<Target, AssemblerError> {
+        if let Ok(address) = operands[i].parse::<Address>() {
+            return Ok(Target::Address(address));
+        }
+        if !is_identifier(operands[i]) {
+            return Err(syntax(number, &format!("Invalid label {}", operands[i])));
+        }
+        Ok(Target::Label(operands[i].to_string()))
+    };
+
+    let instruction = match line.mnemonic {
+        "noop" => Instruction::Noop,
+        "ret" => Instruction::Ret,
+        "move" => Instruction::Move(register(0)?, integer(1)?),
+        "copy" => Instruction::Copy(register(0)?, register(1)?),
+        "add" => Instruction::Add(register(0)?, register(1)?),
+        "sub" => Instruction::Sub(register(0)?, register(1)?),
+        "mul" => Instruction::Mul(register(0)?, register(1)?),
+        "div" => Instruction::Div(register(0)?, register(1)?),
+        "not" => Instruction::Not(register(0)?),
+        "jump" => Instruction::Jump(address(0)?),
+        "call" => Instruction::Call(target(0)?),
+        "jeq" => Instruction::JEQ(integer(0)?, integer(1)?, address(2)?),
+        "jgt" => Instruction::JGT(integer(0)?, integer(1)?, address(2)?),
+        "jge" => Instruction::JGE(integer(0)?, integer(1)?, address(2)?),
+        "la" => Instruction::LA(register(0)?, target(1)?),
+        "lw" => Instruction::LW(register(0)?, integer(1)?),
+        "sw" => Instruction::SW(register(0)?, integer(1)?),
+        "lc" => Instruction::LC(register(0)?, integer(1)?),
+        "lr" => Instruction::LR(register(0)?, register(1)?),
+        "push" => Instruction::Push(integer(0)?),
+        "pushr" => Instruction::PushR(register(0)?),
+        "pop" => Instruction::Pop(register(0)?),
+        "enter" => Instruction::Enter(integer(0)?),
+        "leave" => Instruction::Leave(integer(0)?),
+        "lf" => Instruction::LF(register(0)?, offset(1)?),
+        "sf" => Instruction::SF(register(0)?, offset(1)?),
+        "syscall" => {
+            let syscall = match (operands[0], operands.len()) {
+                ("read", 4) => SysCall::Read {
+                    file_descriptor: integer(1)?,
+                    buf_pointer: integer(2)?,
+                    count: integer(3)?,
+                },
+                ("write", 4) => SysCall::Write {
+                    file_descriptor: integer(1)?,
+                    buf_pointer: integer(2)?,
+                    count: integer(3)?,
+                },
+                ("writefloat", 3) => SysCall::WriteFloat {
+                    file_descriptor: integer(1)?,
+                    buf_pointer: integer(2)?,
+                },
+                ("writeint", 3) => SysCall::WriteInt {
+                    file_descriptor: integer(1)?,
+                    register: register(2)?,
+                },
+                ("writebool", 3) => SysCall::WriteBool {
+                    file_descriptor: integer(1)?,
+                    register: register(2)?,
+                },
+                ("writestring", 3) => SysCall::WriteString {
+                    file_descriptor: integer(1)?,
+                    register: register(2)?,
+                },
+                ("writefloatr", 3) => SysCall::WriteFloatR {
+                    file_descriptor: integer(1)?,
+                    register: register(2)?,
+                },
+                (name, _) => return Err(syntax(number, &format!("Invalid syscall {name}"))),
+            };
+            Instruction::SysCall(syscall)
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(instruction)
+}
+
+fn syntax(line: usize, message: &str) -> AssemblerError {
+    AssemblerError::Syntax { line, message: message.to_string() }
+}
+
+fn strip_comment(line: &str) -> &str {
+    // Comment markers inside string literals don't count
+    let mut in_string = false;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            '/' if !in_string && line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_string(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => string.push('\n'),
+            't' => string.push('\t'),
+            'r' => string.push('\r'),
+            '0' => string.push('\0'),
+            '\\' => string.push('\\'),
+            '"' => string.push('"'),
+            _ => return None,
+        }
+    }
+
+    Some(string)
+}
+
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '\n' => quoted += "\\n",
+            '\t' => quoted += "\\t",
+            '\r' => quoted += "\\r",
+            '\0' => quoted += "\\0",
+            '\\' => quoted += "\\\\",
+            '"' => quoted += "\\\"",
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{Instruction as IrInstruction, Module, Value};
+
+    // Floats that only print as words, strings with spaces, quotes and
+    // something that looks like a comment, and procs that are called and
+    // tail called by label
+    #[test]
+    fn round_trips_compiled_programs() {
+        let source = "
+            var message = \"quote\"
+            proc show(f, s) {
+                println(f, s)
+            }
+            proc again(f, s) {
+                return show(f, s)
+            }
+            show(0.0 / 0.0, message)
+            again(1.0 / 0.0, \"two words\")
+            show(0.0 - (1.0 / 0.0), message)
+        ";
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        crate::constant_folding::fold(&mut module);
+        for block in &mut module.entry.blocks {
+            for instruction in &mut block.instructions {
+                if let IrInstruction::Const(_, Value::String(string)) = instruction {
+                    if string == "quote" {
+                        *string = "say \"hi\" // twice".to_string();
+                    }
+                }
+            }
+        }
+        let vm = VM::from_ir(&module).unwrap();
+
+        let text = disassemble(&vm);
+        for line in [".float NaN", ".float inf", ".float -inf", r#".string "say \"hi\" // twice\0""#, "jump show"] {
+            assert!(text.contains(line), "{line} missing from\n{text}");
+        }
+        let assembled = assemble(&text).unwrap();
+        let constants = |vm: &VM| format!("{:?}", vm.constants().iter().collect::<Vec<_>>());
+        assert_eq!(constants(&assembled), constants(&vm));
+        assert_eq!(format!("{:?}", assembled.instructions()), format!("{:?}", vm.instructions()));
+        assert_eq!(assembled.labels(), vm.labels());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::token::Span;
+
 pub type Identifier = String;
 
 #[derive(Debug, Clone)]
@@ -61,6 +63,16 @@ pub enum Expression {
 
 pub type Block = Program;
 
+// Types a declaration can be annotated with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    String,
+    Null,
+}
+
 #[derive(Debug, Clone)]
 pub struct Assignment {
     pub identifier: Identifier,
@@ -73,18 +85,22 @@ pub type ProcArgs = Vec<Identifier>;
 pub struct Proc {
     pub identifier: Identifier,
     pub proc_args: ProcArgs,
+    pub arg_types: Vec<Option<Type>>, // Of each arg, if annotated
+    pub return_type: Option<Type>,
     pub block: Block,
 }
 
 #[derive(Debug, Clone)]
 pub struct Const {
     pub identifier: Identifier,
+    pub annotation: Option<Type>,
     pub expr: Expression,
 }
 
 #[derive(Debug, Clone)]
 pub struct Var {
     pub identifier: Identifier,
+    pub annotation: Option<Type>,
     pub expr: Expression,
 }
 
@@ -105,5 +121,6 @@ pub enum Item {
 #[derive(Debug, Clone)]
 pub struct Program {
     pub items: Vec<Item>,
+    pub spans: Vec<Span>, // Where each item starts
 }
 
@@ -0,0 +1,788 @@
+use std::fmt;
+use std::path::Path;
+use crate::verifier::{self, VerifyError};
+use crate::vm::*;
+
+// Binary bytecode file, all integers little endian
+//
+//     magic        "FIBC"
+//     version      u16
+//     constants    u32 count, then per constant a tag byte and its payload
+//     labels       u32 count, then per label its name and address
+//     instructions u32 count, then per instruction an opcode and its operands
+//     checksum     u32 FNV-1a hash of everything before it
+//
+// Strings are a u32 byte length followed by UTF-8 bytes. Files come from
+// outside the compiler, so loaded programs are run through the verifier.
+//
+// Snapshots of a running VM use the same layout with magic "FISN" and the
+// run time state between the instructions and the checksum
+//
+//     memory       u32 count, then the words
+//     ret          u32 count, then the return addresses
+//     registers    ip, fp, a, b, c and d as u32
+//     executed     u64
+//     status       tag byte, followed by the return value for Returned
+
+const MAGIC: &[u8; 4] = b"FIBC";
+const SNAPSHOT_MAGIC: &[u8; 4] = b"FISN";
+const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidOpcode(u8),
+    InvalidRegister(u8),
+    InvalidTag(u8),
+    InvalidString,
+    Link(Vec<LinkError>),
+    Verify(Vec<VerifyError>),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "Not a bytecode file"),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {version}, expected {VERSION}")
+            }
+            BytecodeError::ChecksumMismatch => write!(f, "Checksum mismatch, file is corrupt"),
+            BytecodeError::UnexpectedEnd => write!(f, "Unexpected end of file"),
+            BytecodeError::TrailingBytes => write!(f, "Trailing bytes at the end of the file"),
+            BytecodeError::InvalidOpcode(opcode) => write!(f, "Invalid opcode {opcode}"),
+            BytecodeError::InvalidRegister(register) => write!(f, "Invalid register {register}"),
+            BytecodeError::InvalidTag(tag) => write!(f, "Invalid tag {tag}"),
+            BytecodeError::InvalidString => write!(f, "Invalid UTF-8 string"),
+            BytecodeError::Link(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join(", "))
+            }
+            BytecodeError::Verify(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join(", "))
+            }
+            BytecodeError::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+pub fn serialize(vm: &VM) -> Vec<u8> {
+    let mut writer = Writer { bytes: vec![] };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+    writer.program(vm);
+    writer.finish()
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<VM, BytecodeError> {
+    let mut reader = Reader::open(bytes, MAGIC)?;
+    let vm = reader.program()?;
+    reader.end()?;
+    Ok(vm)
+}
+
+// Captures a VM mid run so it can be resumed later, possibly in another
+// process. Limits, tracing and profiling belong to the host and are not saved.
+pub fn snapshot(vm: &VM) -> Vec<u8> {
+    let mut writer = Writer { bytes: vec![] };
+    writer.bytes.extend_from_slice(SNAPSHOT_MAGIC);
+    writer.u16(VERSION);
+    writer.program(vm);
+
+    let state = vm.state();
+    writer.u32(state.memory.len() as u32);
+    for word in &state.memory {
+        writer.u32(*word);
+    }
+    writer.u32(state.ret.len() as u32);
+    for address in &state.ret {
+        writer.u32(*address);
+    }
+    writer.u32(state.ip);
+    writer.u32(state.fp);
+    for register in state.registers {
+        writer.u32(register);
+    }
+    writer.u64(state.executed);
+    match state.status {
+        None => writer.u8(0),
+        Some(ExitStatus::Halted) => writer.u8(1),
+        Some(ExitStatus::Returned(value)) => {
+            writer.u8(2);
+            writer.u32(value);
+        }
+        Some(ExitStatus::Paused) => writer.u8(3),
+    }
+
+    writer.finish()
+}
+
+pub fn restore(bytes: &[u8]) -> Result<VM, BytecodeError> {
+    let mut reader = Reader::open(bytes, SNAPSHOT_MAGIC)?;
+    let mut vm = reader.program()?;
+
+    let mut memory = vec![];
+    for _ in 0..reader.u32()? {
+        memory.push(reader.u32()?);
+    }
+    let mut ret = vec![];
+    for _ in 0..reader.u32()? {
+        ret.push(reader.u32()?);
+    }
+    let ip = reader.u32()?;
+    let fp = reader.u32()?;
+    let registers = [reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?];
+    let executed = reader.u64()?;
+    let status = match reader.u8()? {
+        0 => None,
+        1 => Some(ExitStatus::Halted),
+        2 => Some(ExitStatus::Returned(reader.u32()?)),
+        3 => Some(ExitStatus::Paused),
+        tag => return Err(BytecodeError::InvalidTag(tag)),
+    };
+    reader.end()?;
+
+    vm.restore(VmState { memory, ret, ip, fp, registers, executed, status });
+    Ok(vm)
+}
+
+pub fn write_file(vm: &VM, path: &Path) -> Result<(), BytecodeError> {
+    std::fs::write(path, serialize(vm)).map_err(BytecodeError::Io)
+}
+
+pub fn read_file(path: &Path) -> Result<VM, BytecodeError> {
+    let bytes = std::fs::read(path).map_err(BytecodeError::Io)?;
+    deserialize(&bytes)
+}
+
+pub fn write_snapshot(vm: &VM, path: &Path) -> Result<(), BytecodeError> {
+    std::fs::write(path, snapshot(vm)).map_err(BytecodeError::Io)
+}
+
+pub fn read_snapshot(path: &Path) -> Result<VM, BytecodeError> {
+    let bytes = std::fs::read(path).map_err(BytecodeError::Io)?;
+    restore(&bytes)
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, string: &str) {
+        self.u32(string.len() as u32);
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn register(&mut self, register: &Register) {
+        self.u8(match register {
+            Register::A => 0,
+            Register::B => 1,
+            Register::C => 2,
+            Register::D => 3,
+        });
+    }
+
+    fn program(&mut self, vm: &VM) {
+        self.u32(vm.constants().len() as u32);
+        for constant in vm.constants().iter() {
+            match constant {
+                Constant::String(string) => {
+                    self.u8(0);
+                    self.string(string);
+                }
+                Constant::Float(float) => {
+                    self.u8(1);
+                    self.u64(float.to_bits());
+                }
+            }
+        }
+
+        let mut labels: Vec<(&String, &Address)> = vm.labels().iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        self.u32(labels.len() as u32);
+        for (label, address) in labels {
+            self.string(label);
+            self.u32(*address);
+        }
+
+        self.u32(vm.instructions().len() as u32);
+        for instruction in vm.instructions() {
+            self.instruction(instruction);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let checksum = checksum(&self.bytes);
+        self.u32(checksum);
+        self.bytes
+    }
+
+    fn target(&mut self, target: &Target) {
+        match target {
+            Target::Label(label) => {
+                self.u8(0);
+                self.string(label);
+            }
+            Target::Address(address) => {
+                self.u8(1);
+                self.u32(*address);
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Noop => self.u8(0),
+            Instruction::Ret => self.u8(1),
+            Instruction::Move(to, value) => {
+                self.u8(2);
+                self.register(to);
+                self.u32(*value);
+            }
+            Instruction::Copy(to, from) => {
+                self.u8(3);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::Add(to, from) => {
+                self.u8(4);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::Sub(to, from) => {
+                self.u8(5);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::Mul(to, from) => {
+                self.u8(6);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::Div(to, from) => {
+                self.u8(7);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::Jump(address) => {
+                self.u8(8);
+                self.u32(*address);
+            }
+            Instruction::Call(target) => {
+                self.u8(9);
+                self.target(target);
+            }
+            Instruction::JEQ(a, b, address) => {
+                self.u8(10);
+                self.u32(*a);
+                self.u32(*b);
+                self.u32(*address);
+            }
+            Instruction::JGT(a, b, address) => {
+                self.u8(11);
+                self.u32(*a);
+                self.u32(*b);
+                self.u32(*address);
+            }
+            Instruction::JGE(a, b, address) => {
+                self.u8(12);
+                self.u32(*a);
+                self.u32(*b);
+                self.u32(*address);
+            }
+            Instruction::LA(register, target) => {
+                self.u8(13);
+                self.register(register);
+                self.target(target);
+            }
+            Instruction::LW(register, address) => {
+                self.u8(14);
+                self.register(register);
+                self.u32(*address);
+            }
+            Instruction::SW(register, address) => {
+                self.u8(15);
+                self.register(register);
+                self.u32(*address);
+            }
+            Instruction::LC(register, index) => {
+                self.u8(16);
+                self.register(register);
+                self.u32(*index);
+            }
+            Instruction::Push(value) => {
+                self.u8(17);
+                self.u32(*value);
+            }
+            Instruction::PushR(register) => {
+                self.u8(18);
+                self.register(register);
+            }
+            Instruction::Pop(register) => {
+                self.u8(19);
+                self.register(register);
+            }
+            Instruction::Enter(locals) => {
+                self.u8(20);
+                self.u32(*locals);
+            }
+            Instruction::Leave(args) => {
+                self.u8(21);
+                self.u32(*args);
+            }
+            Instruction::LF(register, offset) => {
+                self.u8(22);
+                self.register(register);
+                self.i32(*offset);
+            }
+            Instruction::SF(register, offset) => {
+                self.u8(23);
+                self.register(register);
+                self.i32(*offset);
+            }
+            Instruction::Not(register) => {
+                self.u8(25);
+                self.register(register);
+            }
+            Instruction::LR(to, from) => {
+                self.u8(26);
+                self.register(to);
+                self.register(from);
+            }
+            Instruction::SysCall(syscall) => {
+                self.u8(24);
+                match syscall {
+                    SysCall::Read { file_descriptor, buf_pointer, count } => {
+                        self.u8(0);
+                        self.u32(*file_descriptor);
+                        self.u32(*buf_pointer);
+                        self.u32(*count);
+                    }
+                    SysCall::Write { file_descriptor, buf_pointer, count } => {
+                        self.u8(1);
+                        self.u32(*file_descriptor);
+                        self.u32(*buf_pointer);
+                        self.u32(*count);
+                    }
+                    SysCall::WriteFloat { file_descriptor, buf_pointer } => {
+                        self.u8(2);
+                        self.u32(*file_descriptor);
+                        self.u32(*buf_pointer);
+                    }
+                    SysCall::WriteInt { file_descriptor, register } => {
+                        self.u8(3);
+                        self.u32(*file_descriptor);
+                        self.register(register);
+                    }
+                    SysCall::WriteBool { file_descriptor, register } => {
+                        self.u8(4);
+                        self.u32(*file_descriptor);
+                        self.register(register);
+                    }
+                    SysCall::WriteString { file_descriptor, register } => {
+                        self.u8(5);
+                        self.u32(*file_descriptor);
+                        self.register(register);
+                    }
+                    SysCall::WriteFloatR { file_descriptor, register } => {
+                        self.u8(6);
+                        self.u32(*file_descriptor);
+                        self.register(register);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    // Checks the header and checksum, leaving the reader after the version
+    fn open(bytes: &'a [u8], magic: &[u8; 4]) -> Result<Self, BytecodeError> {
+        if bytes.len() < magic.len() || &bytes[..magic.len()] != magic {
+            return Err(BytecodeError::BadMagic);
+        }
+
+        let mut reader = Reader { bytes, position: magic.len() };
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        if bytes.len() < reader.position + 4 {
+            return Err(BytecodeError::UnexpectedEnd);
+        }
+        let (body, expected) = bytes.split_at(bytes.len() - 4);
+        if checksum(body) != u32::from_le_bytes(expected.try_into().unwrap()) {
+            return Err(BytecodeError::ChecksumMismatch);
+        }
+
+        Ok(Reader { bytes: body, position: reader.position })
+    }
+
+    fn end(&self) -> Result<(), BytecodeError> {
+        if self.position != self.bytes.len() {
+            return Err(BytecodeError::TrailingBytes);
+        }
+        Ok(())
+    }
+
+    fn program(&mut self) -> Result<VM, BytecodeError> {
+        let mut constants = ConstantPool::new();
+        for _ in 0..self.u32()? {
+            let constant = match self.u8()? {
+                0 => Constant::String(self.string()?),
+                1 => Constant::Float(f64::from_bits(self.u64()?)),
+                tag => return Err(BytecodeError::InvalidTag(tag)),
+            };
+            constants.push(constant);
+        }
+
+        let mut labels = vec![];
+        for _ in 0..self.u32()? {
+            let label = self.string()?;
+            labels.push((label, self.u32()?));
+        }
+
+        let mut instructions = vec![];
+        for _ in 0..self.u32()? {
+            instructions.push(self.instruction()?);
+        }
+
+        let vm = VM::load(instructions, labels, constants).map_err(BytecodeError::Link)?;
+        verifier::verify(&vm).map_err(BytecodeError::Verify)?;
+        Ok(vm)
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.position + count > self.bytes.len() {
+            return Err(BytecodeError::UnexpectedEnd);
+        }
+
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, BytecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, BytecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let length = self.u32()? as usize;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidString)
+    }
+
+    fn register(&mut self) -> Result<Register, BytecodeError> {
+        match self.u8()? {
+            0 => Ok(Register::A),
+            1 => Ok(Register::B),
+            2 => Ok(Register::C),
+            3 => Ok(Register::D),
+            register => Err(BytecodeError::InvalidRegister(register)),
+        }
+    }
+
+    fn target(&mut self) -> Result<Target, BytecodeError> {
+        match self.u8()? {
+            0 => Ok(Target::Label(self.string()?)),
+            1 => Ok(Target::Address(self.u32()?)),
+            tag => Err(BytecodeError::InvalidTag(tag)),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, BytecodeError> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Noop,
+            1 => Instruction::Ret,
+            2 => Instruction::Move(self.register()?, self.u32()?),
+            3 => Instruction::Copy(self.register()?, self.register()?),
+            4 => Instruction::Add(self.register()?, self.register()?),
+            5 => Instruction::Sub(self.register()?, self.register()?),
+            6 => Instruction::Mul(self.register()?, self.register()?),
+            7 => Instruction::Div(self.register()?, self.register()?),
+            8 => Instruction::Jump(self.u32()?),
+            9 => Instruction::Call(self.target()?),
+            10 => Instruction::JEQ(self.u32()?, self.u32()?, self.u32()?),
+            11 => Instruction::JGT(self.u32()?, self.u32()?, self.u32()?),
+            12 => Instruction::JGE(self.u32()?, self.u32()?, self.u32()?),
+            13 => Instruction::LA(self.register()?, self.target()?),
+            14 => Instruction::LW(self.register()?, self.u32()?),
+            15 => Instruction::SW(self.register()?, self.u32()?),
+            16 => Instruction::LC(self.register()?, self.u32()?),
+            17 => Instruction::Push(self.u32()?),
+            18 => Instruction::PushR(self.register()?),
+            19 => Instruction::Pop(self.register()?),
+            20 => Instruction::Enter(self.u32()?),
+            21 => Instruction::Leave(self.u32()?),
+            22 => Instruction::LF(self.register()?, self.i32()?),
+            23 => Instruction::SF(self.register()?, self.i32()?),
+            24 => {
+                let syscall = match self.u8()? {
+                    0 => SysCall::Read {
+                        file_descriptor: self.u32()?,
+                        buf_pointer: self.u32()?,
+                        count: self.u32()?,
+                    },
+                    1 => SysCall::Write {
+                        file_descriptor: self.u32()?,
+                        buf_pointer: self.u32()?,
+                        count: self.u32()?,
+                    },
+                    2 => SysCall::WriteFloat {
+                        file_descriptor: self.u32()?,
+                        buf_pointer: self.u32()?,
+                    },
+                    3 => SysCall::WriteInt {
+                        file_descriptor: self.u32()?,
+                        register: self.register()?,
+                    },
+                    4 => SysCall::WriteBool {
+                        file_descriptor: self.u32()?,
+                        register: self.register()?,
+                    },
+                    5 => SysCall::WriteString {
+                        file_descriptor: self.u32()?,
+                        register: self.register()?,
+                    },
+                    6 => SysCall::WriteFloatR {
+                        file_descriptor: self.u32()?,
+                        register: self.register()?,
+                    },
+                    tag => return Err(BytecodeError::InvalidTag(tag)),
+                };
+                Instruction::SysCall(syscall)
+            }
+            25 => Instruction::Not(self.register()?),
+            26 => Instruction::LR(self.register()?, self.register()?),
+            opcode => return Err(BytecodeError::InvalidOpcode(opcode)),
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::ir::Module;
+
+    // Uses every kind of constant and operand the format encodes
+    const SOURCE: &str = r#"
+        .string "sum "
+        .float 2.5
+        .string "!\0"
+        main:
+            enter 1
+            move a 4
+            sf a 0
+            lf b 0
+            push 3
+            pushr b
+            call add
+            copy c a
+            syscall write 1 0 4
+            syscall writeint 1 c
+            syscall writefloat 1 1
+            lc d 2
+            syscall writestring 1 d
+            lc d 1
+            syscall writefloatr 1 d
+            lr a d
+            syscall writebool 1 a
+            lc d 0
+            la b add
+            lw a 0
+            sw a 5
+            jgt 2 1 skip
+            noop
+        skip:
+            jge 1 1 done
+            not a
+        done:
+            mul c c
+            div c c
+            sub c c
+            jeq 0 0 out
+        out:
+            leave 0
+            ret
+        add:
+            enter 0
+            lf a -2
+            lf b -3
+            add a b
+            leave 2
+            ret
+    "#;
+
+    fn run(mut vm: VM) -> (ExitStatus, String) {
+        vm.capture_output();
+        let status = vm.run().expect("program failed");
+        (status, String::from_utf8(vm.take_output().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = serialize(&assemble(SOURCE).unwrap());
+        let vm = deserialize(&bytes).unwrap();
+        assert_eq!(serialize(&vm), bytes);
+        let expected = run(assemble(SOURCE).unwrap());
+        assert_eq!(expected.1, "sum 72.5!2.5false");
+        assert_eq!(run(vm), expected);
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let mut bytes = serialize(&assemble(SOURCE).unwrap());
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        assert!(matches!(deserialize(&bytes), Err(BytecodeError::ChecksumMismatch)));
+
+        bytes.truncate(middle);
+        assert!(matches!(deserialize(&bytes), Err(BytecodeError::ChecksumMismatch)));
+    }
+
+    // There are no conditionals, so the recursion stops by dividing by zero
+    #[test]
+    fn resumes_snapshots() {
+        let program = "
+            var total = 0
+            proc count(n) {
+                println(n)
+                total = total + n
+                if_zero(n)
+                return count(n - 1)
+            }
+            proc if_zero(n) {
+                return 1 / n
+            }
+            count(5)
+        ";
+        let mut module = Module::from_ast(&crate::parse(program.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        let compile = || VM::from_ir(&module).unwrap();
+        let mut uninterrupted = compile();
+        uninterrupted.capture_output();
+        let error = uninterrupted.run().unwrap_err();
+        let expected = uninterrupted.take_output().unwrap();
+
+        for instructions in [1, 10, 40, 100] {
+            let mut vm = compile();
+            vm.capture_output();
+            assert_eq!(vm.run_for(instructions).unwrap(), ExitStatus::Paused);
+            let mut output = vm.take_output().unwrap();
+
+            let mut resumed = restore(&snapshot(&vm)).unwrap();
+            assert_eq!(resumed.executed(), instructions);
+            resumed.capture_output();
+            assert_eq!(resumed.run().unwrap_err().to_string(), error.to_string());
+            output.extend(resumed.take_output().unwrap());
+            assert_eq!(output, expected);
+        }
+        assert_eq!(String::from_utf8(expected).unwrap(), "5\n4\n3\n2\n1\n0\n");
+    }
+
+    // A host time slicing the VM sees the same program as one that runs it
+    // to the end
+    #[test]
+    fn time_slices_match_a_single_run() {
+        let program = "
+            proc square(n) {
+                println(\"square\", n)
+                return n * n
+            }
+            proc main() {
+                var first = square(3)
+                var total = first + square(4)
+                println(total, 2.5, true, null)
+                return total + 17
+            }
+        ";
+        let mut module = Module::from_ast(&crate::parse(program.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        let (status, expected) = run(VM::from_ir(&module).unwrap());
+        assert_eq!(status, ExitStatus::Returned(42));
+
+        for slice in [1, 2, 7, 1000] {
+            let mut vm = VM::from_ir(&module).unwrap();
+            vm.capture_output();
+            let mut slices = 0;
+            let status = loop {
+                slices += 1;
+                match vm.run_for(slice).unwrap() {
+                    ExitStatus::Paused => continue,
+                    status => break status,
+                }
+            };
+            assert_eq!(status, ExitStatus::Returned(42));
+            assert_eq!(String::from_utf8(vm.take_output().unwrap()).unwrap(), expected);
+            assert_eq!(slices as u64, vm.executed().div_ceil(slice));
+            assert_eq!(vm.run_for(slice).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn rejects_other_versions_and_formats() {
+        let mut bytes = serialize(&assemble(SOURCE).unwrap());
+        bytes[4] = 2;
+        assert!(matches!(deserialize(&bytes), Err(BytecodeError::UnsupportedVersion(2))));
+        assert!(matches!(restore(&serialize(&assemble(SOURCE).unwrap())), Err(BytecodeError::BadMagic)));
+    }
+}
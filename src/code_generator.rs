@@ -1,5 +1,238 @@
-use crate::ast::Program;
+use std::collections::HashMap;
+use crate::ast::BinaryOperator;
+use crate::ir::*;
 
-fn generate_code(root: Program) -> String {
-    todo!()
+// Value model and helpers the generated code is built on
+const RUNTIME: &str = include_str!("runtime.h");
+
+// Compiles a program to C99. Procs become C functions, top level code runs
+// in the C main function and its vars and consts become globals. Names are
+// prefixed, p_ for procs and v_ for variables, so they can't collide with C
+// keywords or the runtime.
+//
+// Every IR temp becomes a C variable, which keeps the order of evaluation
+// left to right. Constants are written where they are used instead.
+pub fn generate_code(module: &Module) -> String {
+    let mut output = String::from(RUNTIME);
+
+    output += "\n";
+    for identifier in &module.globals {
+        output += &format!("static Value v_{identifier};\n");
+    }
+    for proc in &module.procs {
+        output += &format!("static Value {};\n", signature(proc));
+    }
+
+    for proc in &module.procs {
+        let mut function = Writer::new(proc, false);
+        function.function(proc);
+        output += &format!("\nstatic Value {} {{\n{}}}\n", signature(proc), function.body);
+    }
+
+    // Like the VM, the value main returns is the exit status
+    let mut function = Writer::new(&module.entry, true);
+    function.function(&module.entry);
+    output += &format!("\nint main(void) {{\n{}}}\n", function.body);
+
+    output
+}
+
+fn signature(proc: &Function) -> String {
+    let args: Vec<String> = proc.params.iter().map(|arg| format!("Value v_{arg}")).collect();
+    let args = if args.is_empty() { "void".to_string() } else { args.join(", ") };
+    format!("p_{}({args})", proc.name)
+}
+
+struct Writer<'a> {
+    body: String,
+    definitions: HashMap<Temp, &'a Instruction>,
+    exit: bool, // Returns are from the C main function, which needs an int
+}
+
+impl<'a> Writer<'a> {
+    fn new(function: &'a Function, exit: bool) -> Self {
+        Writer {
+            body: String::new(),
+            definitions: function.definitions(),
+            exit,
+        }
+    }
+
+    fn line(&mut self, line: String) {
+        self.body += "    ";
+        self.body += &line;
+        self.body += "\n";
+    }
+
+    fn function(&mut self, function: &Function) {
+        for local in &function.locals {
+            self.line(format!("Value v_{local} = rt_null();"));
+        }
+
+        let uses = function.use_counts();
+        let targets = function.targets();
+        for (id, block) in function.blocks.iter().enumerate() {
+            if targets.contains(&id) {
+                self.body += &format!("b{id}:;\n");
+            }
+
+            for instruction in &block.instructions {
+                let value = match instruction {
+                    Instruction::Const(..) => continue,
+                    Instruction::Store(variable, temp) => {
+                        let value = self.temp(*temp);
+                        self.line(format!("v_{} = {value};", variable.name()));
+                        continue;
+                    }
+                    Instruction::Load(_, variable) => format!("v_{}", variable.name()),
+                    Instruction::Binary(_, operator, left, right) => {
+                        let function = match operator {
+                            BinaryOperator::Plus => "rt_add",
+                            BinaryOperator::Minus => "rt_sub",
+                            BinaryOperator::Multiply => "rt_mul",
+                            BinaryOperator::Divide => "rt_div",
+                        };
+                        format!("{function}({}, {})", self.temp(*left), self.temp(*right))
+                    }
+                    Instruction::Not(_, value) => format!("rt_not({})", self.temp(*value)),
+                    Instruction::Call(_, identifier, args) => format!("p_{identifier}({})", self.temps(args)),
+                    Instruction::Println(_, args) if args.is_empty() => "rt_println(0, NULL)".to_string(),
+                    Instruction::Println(_, args) => {
+                        format!("rt_println({}, (Value[]) {{{}}})", args.len(), self.temps(args))
+                    }
+                };
+
+                match instruction.defines() {
+                    Some(temp) if uses[temp] > 0 => self.line(format!("Value t{temp} = {value};")),
+                    _ => self.line(format!("(void) {value};")),
+                }
+            }
+
+            match block.terminator {
+                Terminator::Return(temp) => {
+                    let value = self.temp(temp);
+                    if self.exit {
+                        self.line(format!("return rt_exit_code({value});"));
+                    } else {
+                        self.line(format!("return {value};"));
+                    }
+                }
+                Terminator::Jump(target) => self.line(format!("goto b{target};")),
+            }
+        }
+    }
+
+    fn temps(&self, temps: &[Temp]) -> String {
+        temps.iter().map(|temp| self.temp(*temp)).collect::<Vec<_>>().join(", ")
+    }
+
+    // The C expression holding the temp's value
+    fn temp(&self, temp: Temp) -> String {
+        match self.definitions.get(&temp) {
+            Some(Instruction::Const(_, value)) => constant(value),
+            _ => format!("t{temp}"),
+        }
+    }
+}
+
+fn constant(value: &Value) -> String {
+    match value {
+        Value::Null => "rt_null()".to_string(),
+        Value::Bool(bool) => format!("rt_bool({})", *bool as u8),
+        Value::Int(int) => match *int {
+            // -9223372036854775808 is negation applied to a literal that
+            // doesn't fit in C
+            i64::MIN => "rt_int(INT64_MIN)".to_string(),
+            int => format!("rt_int(INT64_C({int}))"),
+        },
+        // Folding can make non-finite floats, which have no literal in C
+        Value::Float(float) if float.is_nan() => "rt_float(NAN)".to_string(),
+        Value::Float(float) if float.is_infinite() => {
+            format!("rt_float({}INFINITY)", if *float < 0.0 { "-" } else { "" })
+        }
+        Value::Float(float) => format!("rt_float({float:?})"),
+        Value::String(string) => format!("rt_string({})", quote(string)),
+    }
+}
+
+// C string literal, anything outside printable ASCII is written as an octal
+// escape
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' => quoted += "\\\"",
+            b'\\' => quoted += "\\\\",
+            b'\n' => quoted += "\\n",
+            b'\t' => quoted += "\\t",
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted += &format!("\\{byte:03o}"),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The C code after the runtime
+    fn generate_source(source: &str) -> String {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        crate::constant_folding::fold(&mut module);
+        generate_code(&module)[RUNTIME.len()..].to_string()
+    }
+
+    // Only the top level's return goes through rt_exit_code, procs return
+    // the value itself
+    #[test]
+    fn returns_exit_codes_from_main() {
+        assert_eq!(
+            generate_source("proc id(x) {\n    return x\n}\nprintln(id(\"a\"))\nreturn id(3)"),
+            "
+static Value p_id(Value v_x);
+
+static Value p_id(Value v_x) {
+    Value t0 = v_x;
+    return t0;
+}
+
+int main(void) {
+    Value t1 = p_id(rt_string(\"a\"));
+    (void) rt_println(1, (Value[]) {t1});
+    Value t4 = p_id(rt_int(INT64_C(3)));
+    return rt_exit_code(t4);
+}
+"
+        );
+    }
+
+    // Folding makes these, none of them can be written as a C literal
+    #[test]
+    fn writes_constants_without_a_c_literal() {
+        let code = generate_source("println(9223372036854775807 + 1)\nprintln(0.0 / 0.0, 1.0 / 0.0, 0.0 - 1.0 / 0.0)");
+        assert_eq!(
+            code,
+            "
+
+int main(void) {
+    (void) rt_println(1, (Value[]) {rt_int(INT64_MIN)});
+    (void) rt_println(3, (Value[]) {rt_float(NAN), rt_float(INFINITY), rt_float(-INFINITY)});
+    return rt_exit_code(rt_null());
+}
+"
+        );
+    }
+
+    #[test]
+    fn quotes_strings_as_c_literals() {
+        assert_eq!(quote("plain text"), r#""plain text""#);
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(r"back\slash"), r#""back\\slash""#);
+        assert_eq!(quote("tab\tnew\nline"), r#""tab\tnew\nline""#);
+        assert_eq!(quote("caf\u{e9}"), r#""caf\303\251""#);
+        assert_eq!(quote("\u{1}\r\u{7f}"), r#""\001\015\177""#);
+    }
 }
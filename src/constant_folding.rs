@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::BinaryOperator;
+use crate::diagnostic::Diagnostic;
+use crate::ir::*;
+
+// Folds operations on constants, propagates constants stored in variables to
+// where they are loaded and then removes stores to variables nothing loads
+// and constants nothing uses.
+//
+// Integers fold with the same wrapping as the runtimes, overflow is reported
+// as a warning. Division by zero is an error and is left for the runtime.
+// Only values every backend agrees on are folded, so `!` of a float or a
+// string is left alone.
+pub fn fold(module: &mut Module) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    let fixed = fixed_globals(module);
+    let mut folding = Folding {
+        diagnostics: &mut diagnostics,
+        fixed: &fixed,
+        globals: HashMap::new(),
+    };
+    folding.function(&mut module.entry);
+    folding.globals = constant_globals(&module.entry, &fixed);
+    for proc in &mut module.procs {
+        folding.function(proc);
+    }
+
+    remove_unused(module);
+    diagnostics
+}
+
+// Globals that are stored once, by the top level before it calls anything.
+// No proc can run before that store or change them after it.
+fn fixed_globals(module: &Module) -> HashSet<Variable> {
+    let mut stores: HashMap<&Variable, usize> = HashMap::new();
+    for function in std::iter::once(&module.entry).chain(&module.procs) {
+        for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+            if let Instruction::Store(variable @ Variable::Global(_), _) = instruction {
+                *stores.entry(variable).or_default() += 1;
+            }
+        }
+    }
+
+    let mut fixed = HashSet::new();
+    for instruction in &module.entry.blocks[0].instructions {
+        match instruction {
+            Instruction::Call(..) => break,
+            Instruction::Store(variable @ Variable::Global(_), _) if stores[variable] == 1 => {
+                fixed.insert(variable.clone());
+            }
+            _ => {}
+        }
+    }
+    fixed
+}
+
+// The values of the fixed globals that were stored as constants
+fn constant_globals(entry: &Function, fixed: &HashSet<Variable>) -> HashMap<Variable, Value> {
+    let definitions = entry.definitions();
+    let mut constants = HashMap::new();
+    for instruction in &entry.blocks[0].instructions {
+        if let Instruction::Store(variable, temp) = instruction {
+            if let (true, Some(Instruction::Const(_, value))) = (fixed.contains(variable), definitions.get(temp)) {
+                constants.insert(variable.clone(), value.clone());
+            }
+        }
+    }
+    constants
+}
+
+struct Folding<'a> {
+    diagnostics: &'a mut Vec<Diagnostic>,
+    fixed: &'a HashSet<Variable>,
+    globals: HashMap<Variable, Value>, // Known in every block of a proc
+}
+
+impl Folding<'_> {
+    fn function(&mut self, function: &mut Function) {
+        // Temps are assigned once, so what is known about them holds in
+        // every block. Variables are only followed within a block.
+        let mut known: HashMap<Temp, Value> = HashMap::new();
+        for block in &mut function.blocks {
+            let mut variables = self.globals.clone();
+            for instruction in &mut block.instructions {
+                let folded = match &*instruction {
+                    Instruction::Const(temp, value) => {
+                        known.insert(*temp, value.clone());
+                        None
+                    }
+                    Instruction::Load(temp, variable) => variables.get(variable).map(|value| (*temp, value.clone())),
+                    Instruction::Store(variable, temp) => {
+                        match known.get(temp) {
+                            Some(value) => variables.insert(variable.clone(), value.clone()),
+                            None => variables.remove(variable),
+                        };
+                        None
+                    }
+                    Instruction::Binary(temp, operator, left, right) => match (known.get(left), known.get(right)) {
+                        (Some(left), Some(right)) => {
+                            self.binary(operator, left, right, &function.origins[*temp]).map(|value| (*temp, value))
+                        }
+                        _ => None,
+                    },
+                    Instruction::Not(temp, value) => known.get(value).and_then(not).map(|value| (*temp, value)),
+                    // A proc can change any global
+                    Instruction::Call(..) => {
+                        variables.retain(|variable, _| {
+                            matches!(variable, Variable::Local(_)) || self.fixed.contains(variable)
+                        });
+                        None
+                    }
+                    Instruction::Println(..) => None,
+                };
+
+                if let Some((temp, value)) = folded {
+                    known.insert(temp, value.clone());
+                    *instruction = Instruction::Const(temp, value);
+                }
+            }
+        }
+    }
+
+    // Diagnostics point at where the operation was written, which for inlined
+    // code is in the proc it came from
+    fn binary(&mut self, operator: &BinaryOperator, left: &Value, right: &Value, origin: &Origin) -> Option<Value> {
+        let context = origin.context();
+        let symbol = match operator {
+            BinaryOperator::Plus => "+",
+            BinaryOperator::Minus => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+        };
+
+        match (left, right) {
+            (Value::Int(a), Value::Int(b)) => {
+                let (value, overflow) = match operator {
+                    BinaryOperator::Plus => a.overflowing_add(*b),
+                    BinaryOperator::Minus => a.overflowing_sub(*b),
+                    BinaryOperator::Multiply => a.overflowing_mul(*b),
+                    BinaryOperator::Divide if *b == 0 => {
+                        let message = format!("division by zero {context}: {a} / {b}");
+                        self.diagnostics.push(Diagnostic::error(message).at(origin.span));
+                        return None;
+                    }
+                    BinaryOperator::Divide => a.overflowing_div(*b),
+                };
+                if overflow {
+                    let message = format!("integer overflow {context}: {a} {symbol} {b} wraps to {value}");
+                    self.diagnostics.push(Diagnostic::warning(message).at(origin.span));
+                }
+                Some(Value::Int(value))
+            }
+            (Value::String(a), Value::String(b)) if symbol == "+" => Some(Value::String(format!("{a}{b}"))),
+            _ => {
+                let (a, b) = (number(left)?, number(right)?);
+                let value = match operator {
+                    BinaryOperator::Plus => a + b,
+                    BinaryOperator::Minus => a - b,
+                    BinaryOperator::Multiply => a * b,
+                    BinaryOperator::Divide => a / b,
+                };
+                Some(Value::Float(value))
+            }
+        }
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(int) => Some(*int as f64),
+        Value::Float(float) => Some(*float),
+        _ => None,
+    }
+}
+
+fn not(value: &Value) -> Option<Value> {
+    match value {
+        Value::Null => Some(Value::Bool(true)),
+        Value::Bool(bool) => Some(Value::Bool(!bool)),
+        Value::Int(int) => Some(Value::Bool(*int == 0)),
+        _ => None,
+    }
+}
+
+fn loads(function: &Function) -> impl Iterator<Item = &Variable> {
+    function.blocks.iter().flat_map(|block| &block.instructions).filter_map(|instruction| match instruction {
+        Instruction::Load(_, variable) => Some(variable),
+        _ => None,
+    })
+}
+
+// Locals are only seen by their own proc, globals by every function
+fn remove_unused(module: &mut Module) {
+    let functions = std::iter::once(&module.entry).chain(&module.procs);
+    let globals: HashSet<Variable> = functions
+        .flat_map(loads)
+        .filter(|variable| matches!(variable, Variable::Global(_)))
+        .cloned()
+        .collect();
+    module.globals.retain(|name| globals.contains(&Variable::Global(name.clone())));
+
+    for function in std::iter::once(&mut module.entry).chain(&mut module.procs) {
+        let locals: HashSet<Variable> = loads(function).cloned().collect();
+        let loaded = |variable: &Variable| globals.contains(variable) || locals.contains(variable);
+        for block in &mut function.blocks {
+            block.instructions.retain(|instruction| match instruction {
+                Instruction::Store(variable, _) => loaded(variable),
+                _ => true,
+            });
+        }
+        function.locals.retain(|name| locals.contains(&Variable::Local(name.clone())));
+
+        // Constants and loads don't use any temps, so removing them can't
+        // leave others unused
+        let uses = function.use_counts();
+        for block in &mut function.blocks {
+            block.instructions.retain(|instruction| match instruction {
+                Instruction::Const(temp, _) | Instruction::Load(temp, _) => uses[*temp] > 0,
+                _ => true,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+
+    fn fold_source(source: &str) -> (Module, Vec<Diagnostic>) {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        let diagnostics = fold(&mut module);
+        (module, diagnostics)
+    }
+
+    fn instructions(function: &Function) -> Vec<&Instruction> {
+        function.blocks.iter().flat_map(|block| &block.instructions).collect()
+    }
+
+    // The runtimes wrap the same way, so the folded value is what the program
+    // would compute and overflow is only a warning
+    #[test]
+    fn warns_about_overflow_and_wraps() {
+        let (module, diagnostics) = fold_source("println(9223372036854775807 + 1)");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "warning: integer overflow at the top level: 9223372036854775807 + 1 wraps to -9223372036854775808"
+        );
+        assert_eq!(diagnostics[0].span.unwrap().to_string(), "1:1");
+        assert!(instructions(&module.entry)
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Const(_, Value::Int(i64::MIN)))));
+    }
+
+    #[test]
+    fn reports_division_by_zero_where_it_is_written() {
+        let (module, diagnostics) = fold_source("proc half() {\n    return 10 / 0\n}\nhalf()");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].to_string(), "error: division by zero in proc half: 10 / 0");
+        assert_eq!(diagnostics[0].span.unwrap().to_string(), "2:5");
+        assert!(instructions(&module.procs[0])
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Binary(_, BinaryOperator::Divide, ..))));
+    }
+
+    // x is stored twice, so a proc can't know which value it sees
+    #[test]
+    fn propagates_only_globals_stored_once() {
+        let source = "var x = 1\nvar y = 2\nx = 3\nproc f() {\n    return x + y\n}\nprintln(f())";
+        let module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        let fixed = fixed_globals(&module);
+        assert_eq!(fixed, HashSet::from([Variable::Global("y".to_string())]));
+
+        let (module, _) = fold_source(source);
+        let f = instructions(&module.procs[0]);
+        assert!(matches!(f[0], Instruction::Load(_, Variable::Global(name)) if name == "x"));
+        assert!(matches!(f[1], Instruction::Const(_, Value::Int(2))));
+        assert_eq!(module.globals, ["x"]);
+    }
+}
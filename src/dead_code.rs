@@ -0,0 +1,523 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{Module, Terminator};
+use crate::token::Span;
+
+// Removes code that can never run or whose result is never used, with a
+// warning for everything removed:
+//
+// - Items after a return. Expressions have no branches, so a return
+//   anywhere in an item always leaves the block.
+// - Procs that can't be reached from main or the top level code.
+// - Vars and consts that are assigned but never read. Values that can have
+//   side effects are still evaluated, only the binding goes.
+//
+// Statements after break and continue, and branches whose condition is a
+// constant, are not removed. The grammar has no loops or conditionals yet,
+// so no program can contain them. Once it does, item_returns is where a
+// break or continue has to end the block like a return, and branches need
+// their own pass.
+pub fn eliminate(root: &mut Program) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    after_return(root, true, &mut diagnostics);
+    unreachable_procs(root, &mut diagnostics);
+    while write_only(root, &mut diagnostics) {}
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.map(|span| (span.line, span.column)));
+    diagnostics
+}
+
+// Drops IR blocks that no path from the entry block reaches, like the one
+// falling off the end of a proc that always returns. Nothing is reported,
+// the code they came from is still reachable.
+pub fn remove_unreachable_blocks(module: &mut Module) {
+    for function in std::iter::once(&mut module.entry).chain(&mut module.procs) {
+        // Jumps only go forward, so one pass in order finds every block
+        let mut reachable = vec![false; function.blocks.len()];
+        reachable[0] = true;
+        for (id, block) in function.blocks.iter().enumerate() {
+            if reachable[id] {
+                for successor in block.terminator.successors() {
+                    reachable[successor] = true;
+                }
+            }
+        }
+
+        let mut ids = vec![0; function.blocks.len()];
+        let mut next = 0;
+        for (id, reachable) in reachable.iter().enumerate() {
+            if *reachable {
+                ids[id] = next;
+                next += 1;
+            }
+        }
+
+        let blocks = std::mem::take(&mut function.blocks);
+        for (mut block, reachable) in blocks.into_iter().zip(reachable) {
+            if reachable {
+                if let Terminator::Jump(target) = &mut block.terminator {
+                    *target = ids[*target];
+                }
+                function.blocks.push(block);
+            }
+        }
+    }
+}
+
+fn after_return(block: &mut Block, top_level: bool, diagnostics: &mut Vec<Diagnostic>) {
+    for item in &mut block.items {
+        if let Item::Declaration(Declaration::Proc(proc)) = item {
+            after_return(&mut proc.block, false, diagnostics);
+        }
+    }
+
+    let Some(end) = block.items.iter().position(item_returns) else {
+        return;
+    };
+
+    // Procs are hoisted, so the ones after the return are still declared
+    let mut removed = vec![];
+    let items = std::mem::take(&mut block.items);
+    let spans = std::mem::take(&mut block.spans);
+    for (index, (item, span)) in items.into_iter().zip(spans).enumerate() {
+        if index <= end || matches!(item, Item::Declaration(Declaration::Proc(_))) {
+            block.items.push(item);
+            block.spans.push(span);
+        } else {
+            removed.push((item, span));
+        }
+    }
+    let Some((_, first)) = removed.first() else {
+        return;
+    };
+    diagnostics.push(Diagnostic::warning("unreachable code after return".to_string()).at(*first));
+
+    // Code before the return can still use a name declared after it, it
+    // stays declared and holds null
+    for (item, span) in removed {
+        if let Item::Declaration(Declaration::Var(Var { identifier, .. }) | Declaration::Const(Const { identifier, .. })) = item {
+            if reads(block, &identifier) || (top_level && procs_read(block, &identifier)) {
+                let null = Expression::Unary(Unary::Call(Call::Primary(Primary::Null)));
+                block.items.push(Item::Declaration(Declaration::Var(Var { identifier, annotation: None, expr: null })));
+                block.spans.push(span);
+            }
+        }
+    }
+}
+
+fn unreachable_procs(root: &mut Program, diagnostics: &mut Vec<Diagnostic>) {
+    let mut calls: HashMap<Identifier, HashSet<Identifier>> = HashMap::new();
+    collect_calls(root, &mut calls);
+
+    let mut reachable = HashSet::new();
+    let mut pending: Vec<Identifier> = names(root).into_iter().collect();
+    if calls.contains_key("main") {
+        pending.push("main".to_string());
+    }
+    while let Some(name) = pending.pop() {
+        if reachable.insert(name.clone()) {
+            pending.extend(calls.get(&name).into_iter().flatten().cloned());
+        }
+    }
+
+    remove_procs(root, &reachable, diagnostics);
+}
+
+// Procs and the names they call or refer to, every proc can see every other
+fn collect_calls(block: &Block, calls: &mut HashMap<Identifier, HashSet<Identifier>>) {
+    for item in &block.items {
+        if let Item::Declaration(Declaration::Proc(proc)) = item {
+            calls.entry(proc.identifier.clone()).or_default().extend(names(&proc.block));
+            collect_calls(&proc.block, calls);
+        }
+    }
+}
+
+// Names read or called by the block's own items
+fn names(block: &Block) -> HashSet<Identifier> {
+    let mut names = HashSet::new();
+    for expression in expressions(block) {
+        names_in(expression, &mut names);
+    }
+    names
+}
+
+// A removed proc takes its nested procs with it, unless they are reachable,
+// then they are hoisted to where it was
+fn remove_procs(block: &mut Block, reachable: &HashSet<Identifier>, diagnostics: &mut Vec<Diagnostic>) {
+    let items = std::mem::take(&mut block.items);
+    let spans = std::mem::take(&mut block.spans);
+    for (item, span) in items.into_iter().zip(spans) {
+        match item {
+            Item::Declaration(Declaration::Proc(mut proc)) => {
+                remove_procs(&mut proc.block, reachable, diagnostics);
+                if reachable.contains(&proc.identifier) {
+                    block.items.push(Item::Declaration(Declaration::Proc(proc)));
+                    block.spans.push(span);
+                    continue;
+                }
+
+                let message = format!("proc {} is never called", proc.identifier);
+                diagnostics.push(Diagnostic::warning(message).at(span));
+                for (item, span) in proc.block.items.into_iter().zip(proc.block.spans) {
+                    if let Item::Declaration(Declaration::Proc(_)) = item {
+                        block.items.push(item);
+                        block.spans.push(span);
+                    }
+                }
+            }
+            item => {
+                block.items.push(item);
+                block.spans.push(span);
+            }
+        }
+    }
+}
+
+// Removes one round of write-only variables, returning whether any were.
+// Removing one can leave others that were only read by it write-only.
+fn write_only(root: &mut Program, diagnostics: &mut Vec<Diagnostic>) -> bool {
+    let mut unread = vec![];
+    for (identifier, span) in declarations(root) {
+        if !reads(root, &identifier) && !procs_read(root, &identifier) {
+            unread.push((identifier, span));
+        }
+    }
+    let removed = !unread.is_empty();
+    for (identifier, span) in unread {
+        diagnostics.push(Diagnostic::warning(format!("{identifier} is assigned but never read")).at(span));
+        remove_writes(root, &identifier, true, true);
+    }
+
+    let locals = write_only_locals(root, diagnostics);
+    removed || locals
+}
+
+fn write_only_locals(block: &mut Block, diagnostics: &mut Vec<Diagnostic>) -> bool {
+    let mut removed = false;
+    for item in &mut block.items {
+        if let Item::Declaration(Declaration::Proc(proc)) = item {
+            for (identifier, span) in declarations(&proc.block) {
+                if !reads(&proc.block, &identifier) {
+                    diagnostics.push(Diagnostic::warning(format!("{identifier} is assigned but never read")).at(span));
+                    remove_writes(&mut proc.block, &identifier, true, false);
+                    removed = true;
+                }
+            }
+            removed |= write_only_locals(&mut proc.block, diagnostics);
+        }
+    }
+    removed
+}
+
+// Vars and consts declared in the block, where they are first declared
+fn declarations(block: &Block) -> Vec<(Identifier, Span)> {
+    let mut declared: Vec<(Identifier, Span)> = vec![];
+    for (item, span) in block.items.iter().zip(&block.spans) {
+        if let Item::Declaration(Declaration::Var(Var { identifier, .. }) | Declaration::Const(Const { identifier, .. })) = item {
+            if !declared.iter().any(|(name, _)| name == identifier) {
+                declared.push((identifier.clone(), *span));
+            }
+        }
+    }
+    declared
+}
+
+// Drops the bindings of the variable, keeping values that can have side
+// effects as expressions. `here` is whether the block's own items refer to
+// the variable, `global` whether procs in the block can see it.
+fn remove_writes(block: &mut Block, identifier: &Identifier, here: bool, global: bool) {
+    let items = std::mem::take(&mut block.items);
+    let spans = std::mem::take(&mut block.spans);
+    for (item, span) in items.into_iter().zip(spans) {
+        let item = match item {
+            Item::Assignment(Assignment { identifier: name, expr })
+            | Item::Declaration(Declaration::Var(Var { identifier: name, expr, .. }))
+            | Item::Declaration(Declaration::Const(Const { identifier: name, expr, .. }))
+                if here && name == *identifier =>
+            {
+                if is_pure(&expr) {
+                    continue;
+                }
+                Item::Expression(expr)
+            }
+            Item::Declaration(Declaration::Proc(mut proc)) if global => {
+                let shadowed = shadows(&proc, identifier);
+                remove_writes(&mut proc.block, identifier, !shadowed, true);
+                Item::Declaration(Declaration::Proc(proc))
+            }
+            item => item,
+        };
+        block.items.push(item);
+        block.spans.push(span);
+    }
+}
+
+// Whether the proc has its own variable with the name
+fn shadows(proc: &Proc, identifier: &Identifier) -> bool {
+    proc.proc_args.contains(identifier) || declarations(&proc.block).iter().any(|(name, _)| name == identifier)
+}
+
+// Whether the block reads the name, not counting procs declared in it
+fn reads(block: &Block, identifier: &Identifier) -> bool {
+    names(block).contains(identifier)
+}
+
+// Whether a proc declared in the block, or nested in one, reads the global
+fn procs_read(block: &Block, identifier: &Identifier) -> bool {
+    block.items.iter().any(|item| match item {
+        Item::Declaration(Declaration::Proc(proc)) => {
+            (!shadows(proc, identifier) && reads(&proc.block, identifier)) || procs_read(&proc.block, identifier)
+        }
+        _ => false,
+    })
+}
+
+// Expressions of the block's own items
+fn expressions(block: &Block) -> impl Iterator<Item = &Expression> {
+    block.items.iter().filter_map(|item| match item {
+        Item::Assignment(Assignment { expr, .. })
+        | Item::Declaration(Declaration::Var(Var { expr, .. }))
+        | Item::Declaration(Declaration::Const(Const { expr, .. }))
+        | Item::Expression(expr) => Some(expr),
+        Item::Declaration(Declaration::Proc(_)) => None,
+    })
+}
+
+// Identifiers read and procs called in the expression
+fn names_in(expression: &Expression, names: &mut HashSet<Identifier>) {
+    match expression {
+        Expression::Unary(unary) => unary_names_in(unary, names),
+        Expression::Binary(Binary { left, right, .. }) => {
+            unary_names_in(left, names);
+            names_in(right, names);
+        }
+    }
+}
+
+fn unary_names_in(unary: &Unary, names: &mut HashSet<Identifier>) {
+    match unary {
+        Unary::UnaryOperation { unary, .. } => unary_names_in(unary, names),
+        Unary::Call(Call::CallLiteral { identifier, call_args }) => {
+            names.insert(identifier.clone());
+            for arg in call_args {
+                names_in(arg, names);
+            }
+        }
+        Unary::Call(Call::Primary(Primary::Identifier(identifier))) => {
+            names.insert(identifier.clone());
+        }
+        Unary::Call(Call::Primary(Primary::Return(expression) | Primary::Expression(expression))) => {
+            names_in(expression, names)
+        }
+        Unary::Call(Call::Primary(_)) => {}
+    }
+}
+
+fn item_returns(item: &Item) -> bool {
+    match item {
+        Item::Assignment(Assignment { expr, .. })
+        | Item::Declaration(Declaration::Var(Var { expr, .. }))
+        | Item::Declaration(Declaration::Const(Const { expr, .. }))
+        | Item::Expression(expr) => returns(expr),
+        Item::Declaration(Declaration::Proc(_)) => false,
+    }
+}
+
+fn returns(expression: &Expression) -> bool {
+    match expression {
+        Expression::Unary(unary) => unary_returns(unary),
+        Expression::Binary(Binary { left, right, .. }) => unary_returns(left) || returns(right),
+    }
+}
+
+fn unary_returns(unary: &Unary) -> bool {
+    match unary {
+        Unary::UnaryOperation { unary, .. } => unary_returns(unary),
+        Unary::Call(Call::CallLiteral { call_args, .. }) => call_args.iter().any(returns),
+        Unary::Call(Call::Primary(Primary::Return(_))) => true,
+        Unary::Call(Call::Primary(Primary::Expression(expression))) => returns(expression),
+        Unary::Call(Call::Primary(_)) => false,
+    }
+}
+
+// Literals, variables and `!` of them can't fail or have side effects,
+// arithmetic can fail at runtime
+fn is_pure(expression: &Expression) -> bool {
+    match expression {
+        Expression::Unary(unary) => unary_is_pure(unary),
+        Expression::Binary(_) => false,
+    }
+}
+
+fn unary_is_pure(unary: &Unary) -> bool {
+    match unary {
+        Unary::UnaryOperation { unary, .. } => unary_is_pure(unary),
+        Unary::Call(Call::CallLiteral { .. }) => false,
+        Unary::Call(Call::Primary(Primary::Return(_))) => false,
+        Unary::Call(Call::Primary(Primary::Expression(expression))) => is_pure(expression),
+        Unary::Call(Call::Primary(_)) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ir::{Block as IrBlock, Function, Instruction, Origin, Value};
+
+    // The warnings with where they point, and the program that is left
+    fn eliminate_source(source: &str) -> (Vec<String>, Module) {
+        let mut root = crate::parse(source.to_string());
+        let diagnostics = eliminate(&mut root)
+            .iter()
+            .map(|diagnostic| format!("{} at {}", diagnostic, diagnostic.span.unwrap()))
+            .collect();
+        let mut module = Module::from_ast(&root).unwrap();
+        remove_unreachable_blocks(&mut module);
+        (diagnostics, module)
+    }
+
+    #[test]
+    fn removes_code_after_return() {
+        let (diagnostics, module) = eliminate_source(
+            "proc f() {
+    println(1)
+    return 2
+    println(3)
+    println(4)
+}
+f()
+return 0
+println(5)",
+        );
+        assert_eq!(
+            diagnostics,
+            [
+                "warning: unreachable code after return at 4:5",
+                "warning: unreachable code after return at 9:1",
+            ]
+        );
+        assert_eq!(
+            module.to_string(),
+            "proc <top level>() locals()
+b0:
+    t0 = call f()
+    t1 = 0
+    return t1
+
+proc f() locals()
+b0:
+    t0 = 1
+    t1 = println(t0)
+    t2 = 2
+    return t2
+"
+        );
+    }
+
+    // Procs only reached from an unused one go with it
+    #[test]
+    fn removes_procs_that_are_never_called() {
+        let (diagnostics, module) = eliminate_source(
+            "proc used() {
+    return 1
+}
+proc unused() {
+    return helper()
+}
+proc helper() {
+    return 2
+}
+proc main() {
+    return used()
+}",
+        );
+        assert_eq!(
+            diagnostics,
+            [
+                "warning: proc unused is never called at 4:1",
+                "warning: proc helper is never called at 7:1",
+            ]
+        );
+        let names: Vec<&str> = module.procs.iter().map(|proc| proc.name.as_str()).collect();
+        assert_eq!(names, ["used", "main"]);
+    }
+
+    // `a` is only read by `b`, so it goes once `b` does. The call that
+    // initializes `c` stays for its output.
+    #[test]
+    fn removes_vars_that_are_never_read() {
+        let (diagnostics, module) = eliminate_source(
+            "var a = 1
+var b = a
+var c = println(2)
+proc f(x) {
+    var y = x
+    return x
+}
+f(1)",
+        );
+        assert_eq!(
+            diagnostics,
+            [
+                "warning: a is assigned but never read at 1:1",
+                "warning: b is assigned but never read at 2:1",
+                "warning: c is assigned but never read at 3:1",
+                "warning: y is assigned but never read at 5:5",
+            ]
+        );
+        assert_eq!(
+            module.to_string(),
+            "proc <top level>() locals()
+b0:
+    t0 = 2
+    t1 = println(t0)
+    t2 = 1
+    t3 = call f(t2)
+    t4 = null
+    return t4
+
+proc f(x) locals()
+b0:
+    t0 = x
+    return t0
+"
+        );
+    }
+
+    #[test]
+    fn removes_blocks_nothing_jumps_to() {
+        let block = |instructions, terminator| IrBlock { instructions, terminator };
+        let entry = Function {
+            name: String::new(),
+            params: vec![],
+            locals: vec![],
+            blocks: vec![
+                block(vec![Instruction::Const(0, Value::Int(1))], Terminator::Jump(2)),
+                block(vec![Instruction::Const(1, Value::Int(2))], Terminator::Return(1)),
+                block(vec![Instruction::Println(2, vec![0])], Terminator::Jump(4)),
+                block(vec![], Terminator::Return(0)),
+                block(vec![], Terminator::Return(2)),
+            ],
+            temps: 3,
+            origins: vec![Origin { proc: String::new(), span: Span { line: 1, column: 1 } }; 3],
+        };
+        let mut module = Module { globals: vec![], procs: vec![], entry };
+
+        remove_unreachable_blocks(&mut module);
+        assert_eq!(
+            module.entry.to_string(),
+            "proc <top level>() locals()
+b0:
+    t0 = 1
+    jump b1
+b1:
+    t2 = println(t0)
+    jump b2
+b2:
+    return t2
+"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use crate::assembler::{format_instruction, label_names};
+use crate::vm::*;
+
+const HELP: &str = "\
+break <address | label | line N>   set a breakpoint (b)
+delete <address | label | line N>  remove a breakpoint (d)
+step                               execute one instruction (s)
+next                               step over calls (n)
+continue                           run to the next breakpoint (c)
+registers                          show registers (r)
+memory [start] [count]             show memory words (m)
+stack                              show the call stack (bt)
+labels                             show all labels
+list                               show instructions around ip (l)
+quit                               leave the debugger (q)";
+
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Exited(ExitStatus),
+    Failed(VmError),
+}
+
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<Address>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+        }
+    }
+
+    pub fn run(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "Type help for a list of commands")?;
+        self.print_location(output)?;
+
+        loop {
+            write!(output, "(debug) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some(&command) = words.first() else {
+                continue;
+            };
+            let arguments = &words[1..];
+
+            match command {
+                "help" | "h" => writeln!(output, "{HELP}")?,
+                "break" | "b" => match self.parse_location(arguments) {
+                    Some(address) => {
+                        self.breakpoints.insert(address);
+                        writeln!(output, "Breakpoint at {}", self.location(address))?;
+                    }
+                    None => writeln!(output, "Unknown location {}", arguments.join(" "))?,
+                },
+                "delete" | "d" => match self.parse_location(arguments) {
+                    Some(address) if self.breakpoints.remove(&address) => {
+                        writeln!(output, "Deleted breakpoint at {}", self.location(address))?;
+                    }
+                    _ => writeln!(output, "No breakpoint at {}", arguments.join(" "))?,
+                },
+                "step" | "s" => self.resume(None, true, output)?,
+                "next" | "n" => {
+                    let over_call = matches!(
+                        self.vm.instructions().get(self.vm.ip() as usize),
+                        Some(Instruction::Call(_))
+                    );
+                    let depth = over_call.then(|| self.vm.call_stack().len());
+                    self.resume(depth, depth.is_none(), output)?;
+                }
+                "continue" | "c" => self.resume(None, false, output)?,
+                "registers" | "r" => self.print_registers(output)?,
+                "memory" | "m" => {
+                    let start = arguments.first().and_then(|a| a.parse().ok()).unwrap_or(0);
+                    let count = arguments.get(1).and_then(|a| a.parse().ok()).unwrap_or(16);
+                    self.print_memory(start, count, output)?;
+                }
+                "stack" | "bt" => self.print_stack(output)?,
+                "labels" => self.print_labels(output)?,
+                "list" | "l" => self.print_listing(output)?,
+                "quit" | "q" => return Ok(()),
+                _ => writeln!(output, "Unknown command {command}, type help for a list of commands")?,
+            }
+        }
+    }
+
+    // Runs until the program stops, a breakpoint is hit or, when stepping
+    // over a call, the call stack is back to `depth`
+    fn resume(&mut self, depth: Option<usize>, single: bool, output: &mut impl Write) -> io::Result<()> {
+        if self.finished {
+            return writeln!(output, "The program has finished");
+        }
+
+        let stop = loop {
+            match self.vm.step() {
+                Ok(Some(status)) => break Stop::Exited(status),
+                Err(error) => break Stop::Failed(error),
+                Ok(None) => {}
+            }
+
+            if single || depth.is_some_and(|depth| self.vm.call_stack().len() <= depth) {
+                break Stop::Stepped;
+            }
+            if self.breakpoints.contains(&self.vm.ip()) {
+                break Stop::Breakpoint;
+            }
+        };
+
+        match stop {
+            Stop::Stepped => self.print_location(output),
+            Stop::Breakpoint => {
+                writeln!(output, "Hit breakpoint")?;
+                self.print_location(output)
+            }
+            Stop::Exited(status) => {
+                self.finished = true;
+                writeln!(output, "Program exited: {status:?}")
+            }
+            Stop::Failed(error) => {
+                self.finished = true;
+                writeln!(output, "Program failed: {error}")
+            }
+        }
+    }
+
+    fn parse_location(&self, arguments: &[&str]) -> Option<Address> {
+        match arguments {
+            ["line", line] => self.vm.address_of_line(line.parse().ok()?),
+            [location] => match location.parse::<Address>() {
+                Ok(address) => Some(address),
+                Err(_) => self.vm.labels().get(*location).copied(),
+            },
+            _ => None,
+        }
+    }
+
+    // Address relative to the closest label before it, e.g. 12 <fact+2>
+    fn location(&self, address: Address) -> String {
+        let closest = self
+            .vm
+            .labels()
+            .iter()
+            .filter(|(_, a)| **a <= address)
+            .max_by(|x, y| x.1.cmp(y.1).then(y.0.cmp(x.0)));
+
+        let mut location = match closest {
+            Some((label, a)) if *a == address => format!("{address} <{label}>"),
+            Some((label, a)) => format!("{address} <{label}+{}>", address - a),
+            None => address.to_string(),
+        };
+        if let Some(line) = self.vm.source_line(address) {
+            location += &format!(" line {line}");
+        }
+        location
+    }
+
+    fn print_location(&self, output: &mut impl Write) -> io::Result<()> {
+        let ip = self.vm.ip();
+        match self.vm.instructions().get(ip as usize) {
+            Some(instruction) => {
+                let text = format_instruction(instruction, &label_names(self.vm.labels()));
+                writeln!(output, "{}: {text}", self.location(ip))
+            }
+            None => writeln!(output, "{ip}: end of program"),
+        }
+    }
+
+    fn print_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        for (name, register) in [("a", Register::A), ("b", Register::B), ("c", Register::C), ("d", Register::D)] {
+            let value = self.vm.register(register);
+            writeln!(output, "{name}  {value:<12} {:#010x}", value)?;
+        }
+        writeln!(output, "ip {}", self.vm.ip())?;
+        writeln!(output, "fp {}", self.vm.fp())
+    }
+
+    fn print_memory(&self, start: usize, count: usize, output: &mut impl Write) -> io::Result<()> {
+        let memory = self.vm.memory();
+        if start >= memory.len() {
+            return writeln!(output, "Memory has {} words", memory.len());
+        }
+
+        let end = memory.len().min(start.saturating_add(count));
+        for (address, word) in memory[start..end].iter().enumerate() {
+            writeln!(output, "{:>6}: {word:<12} {word:#010x}", start + address)?;
+        }
+        Ok(())
+    }
+
+    fn print_stack(&self, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "#0 {}", self.location(self.vm.ip()))?;
+        for (depth, address) in self.vm.call_stack().iter().rev().enumerate() {
+            writeln!(output, "#{} {}", depth + 1, self.location(*address))?;
+        }
+        Ok(())
+    }
+
+    fn print_labels(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut labels: Vec<(&String, &Address)> = self.vm.labels().iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        for (label, address) in labels {
+            writeln!(output, "{address:>6}: {label}")?;
+        }
+        Ok(())
+    }
+
+    fn print_listing(&self, output: &mut impl Write) -> io::Result<()> {
+        let ip = self.vm.ip() as usize;
+        let names = label_names(self.vm.labels());
+        let start = ip.saturating_sub(5);
+        let end = self.vm.instructions().len().min(ip + 6);
+
+        for address in start..end {
+            let marker = if address == ip { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&(address as Address)) { "*" } else { " " };
+            let text = format_instruction(&self.vm.instructions()[address], &names);
+            writeln!(output, "{marker}{breakpoint}{address:>5}: {text}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const SOURCE: &str = "
+        main:
+            move a 2
+            push 3
+            pushr a
+            call add
+            ret
+        add:
+            enter 0
+            lf a -2
+            lf b -3
+            add a b
+            leave 2
+            ret
+    ";
+
+    fn debug(commands: &str) -> String {
+        let mut vm = assemble(SOURCE).unwrap();
+        vm.capture_output();
+        let mut output = vec![];
+        Debugger::new(vm).run(&mut commands.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let output = debug("b add\nb line 11\nc\nc\nd add\nd add\nc\nc\n");
+        assert!(output.contains("Breakpoint at 5 <add> line 9\n"));
+        assert!(output.contains("Breakpoint at 7 <add+2> line 11\n"));
+        assert!(output.contains("Hit breakpoint\n5 <add> line 9: enter 0\n"));
+        assert!(output.contains("Hit breakpoint\n7 <add+2> line 11: lf b -3\n"));
+        assert!(output.contains("Deleted breakpoint at 5 <add> line 9\n"));
+        assert!(output.contains("No breakpoint at add\n"));
+        assert!(output.ends_with("Program exited: Returned(5)\n(debug) The program has finished\n(debug) "));
+    }
+
+    #[test]
+    fn steps_into_and_over_calls() {
+        let into = debug("s\ns\ns\ns\nbt\n");
+        assert!(into.contains("(debug) 3 <main+3> line 6: call add\n(debug) 5 <add> line 9: enter 0\n"));
+        assert!(into.contains("#0 5 <add> line 9\n#1 4 <main+4> line 7\n"));
+
+        let over = debug("s\ns\ns\nn\nn\n");
+        assert!(over.contains("(debug) 3 <main+3> line 6: call add\n(debug) 4 <main+4> line 7: ret\n"));
+        assert!(over.ends_with("Program exited: Returned(5)\n(debug) "));
+    }
+
+    #[test]
+    fn prints_registers_and_memory() {
+        let output = debug("b add\nc\ns\nr\nm 0 18446744073709551615\nm 1 1\nm 9\n");
+        let registers = "a  2            0x00000002\n\
+                         b  0            0x00000000\n\
+                         c  0            0x00000000\n\
+                         d  0            0x00000000\n\
+                         ip 6\n\
+                         fp 3\n";
+        assert!(output.contains(registers));
+        let memory = "     0: 3            0x00000003\n     1: 2            0x00000002\n     2: 0            0x00000000\n";
+        assert!(output.contains(&format!("(debug) {memory}(debug)      1: 2            0x00000002\n(debug) ")));
+        assert!(output.contains("Memory has 3 words\n"));
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::Identifier;
+use crate::ir::*;
+
+// Procs with at most this many instructions, counting terminators, are
+// inlined. Sizes are measured after the calls in the proc were inlined.
+const SIZE_LIMIT: usize = 16;
+
+// Replaces calls to small procs with their bodies, then removes the procs
+// that are no longer called. Procs that can call themselves, directly or
+// through other procs, are never inlined.
+//
+// Args are evaluated into temps before the call, so they are still evaluated
+// in order, and the inlined body stores them into the params, which become
+// locals of the caller. A body with a single block is spliced in at the call.
+// Otherwise its returns store the value and jump to a block with the rest of
+// the caller, so an early return only leaves the inlined body.
+pub fn inline(module: &mut Module) {
+    let Module { globals, procs, entry } = module;
+    let calls: HashMap<Identifier, Vec<Identifier>> =
+        procs.iter().map(|proc| (proc.name.clone(), callees(proc))).collect();
+
+    // Callees are inlined into their callers before those are measured
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    for proc in procs.iter() {
+        postorder(&proc.name, &calls, &mut visited, &mut order);
+    }
+
+    let mut inlinable: HashMap<Identifier, Function> = HashMap::new();
+    for name in order {
+        let proc = procs.iter_mut().find(|proc| proc.name == name).unwrap();
+        inline_calls(proc, &inlinable, globals);
+        if size(proc) <= SIZE_LIMIT && !reaches(&calls, &name, &name) {
+            inlinable.insert(name, proc.clone());
+        }
+    }
+    inline_calls(entry, &inlinable, globals);
+
+    // Procs stay if the top level can still reach them
+    let calls: HashMap<Identifier, Vec<Identifier>> =
+        procs.iter().map(|proc| (proc.name.clone(), callees(proc))).collect();
+    let mut reachable = HashSet::new();
+    let mut pending = callees(entry);
+    while let Some(name) = pending.pop() {
+        if reachable.insert(name.clone()) {
+            pending.extend(calls[&name].iter().cloned());
+        }
+    }
+    procs.retain(|proc| reachable.contains(&proc.name));
+}
+
+// Procs the function calls, in the order the calls appear
+fn callees(function: &Function) -> Vec<Identifier> {
+    let mut names = vec![];
+    for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+        if let Instruction::Call(_, name, _) = instruction {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    names
+}
+
+fn postorder(
+    name: &Identifier,
+    calls: &HashMap<Identifier, Vec<Identifier>>,
+    visited: &mut HashSet<Identifier>,
+    order: &mut Vec<Identifier>,
+) {
+    if !visited.insert(name.clone()) {
+        return;
+    }
+    for callee in &calls[name] {
+        postorder(callee, calls, visited, order);
+    }
+    order.push(name.clone());
+}
+
+// Whether a call to `from` can lead to a call to `to`
+fn reaches(calls: &HashMap<Identifier, Vec<Identifier>>, from: &Identifier, to: &Identifier) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = calls[from].clone();
+    while let Some(name) = pending.pop() {
+        if &name == to {
+            return true;
+        }
+        if visited.insert(name.clone()) {
+            pending.extend(calls[&name].iter().cloned());
+        }
+    }
+    false
+}
+
+fn size(function: &Function) -> usize {
+    function.blocks.iter().map(|block| block.instructions.len() + 1).sum()
+}
+
+fn inline_calls(function: &mut Function, inlinable: &HashMap<Identifier, Function>, globals: &[Identifier]) {
+    let (mut block, mut index) = (0, 0);
+    while block < function.blocks.len() {
+        if index == function.blocks[block].instructions.len() {
+            (block, index) = (block + 1, 0);
+            continue;
+        }
+        (block, index) = match &function.blocks[block].instructions[index] {
+            Instruction::Call(_, name, _) if inlinable.contains_key(name) => {
+                inline_call(function, block, index, &inlinable[name], globals)
+            }
+            _ => (block, index + 1),
+        };
+    }
+}
+
+// Inlines the call at the index in the block and returns where the rest of
+// the caller continues
+fn inline_call(
+    function: &mut Function,
+    block: BlockId,
+    index: usize,
+    callee: &Function,
+    globals: &[Identifier],
+) -> (BlockId, usize) {
+    let Instruction::Call(result, _, args) = function.blocks[block].instructions[index].clone() else {
+        unreachable!()
+    };
+
+    // Jumps only go forward, so an inlined body runs at most once per call of
+    // the caller and its locals start out as they would in a frame of its own
+    let mut variables = HashMap::new();
+    for name in callee.params.iter().chain(&callee.locals) {
+        let local = fresh(function, globals, &callee.name, name);
+        function.locals.push(local.clone());
+        variables.insert(Variable::Local(name.clone()), Variable::Local(local));
+    }
+    let variable = |variable: &Variable| variables.get(variable).unwrap_or(variable).clone();
+
+    let offset = function.temps;
+    function.temps += callee.temps;
+    function.origins.extend(callee.origins.iter().cloned());
+    let temp = |temp: Temp| temp + offset;
+
+    let mut instructions: Vec<Instruction> = callee
+        .params
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| Instruction::Store(variable(&Variable::Local(param.clone())), arg))
+        .collect();
+
+    if let [Block { instructions: body, terminator: Terminator::Return(value) }] = callee.blocks.as_slice() {
+        instructions.extend(body.iter().map(|instruction| rename(instruction, temp, variable)));
+        let end = index + instructions.len();
+        function.blocks[block].instructions.splice(index..=index, instructions);
+
+        let value = temp(*value);
+        for instruction in function.blocks.iter_mut().flat_map(|block| &mut block.instructions) {
+            *instruction = rename(instruction, |temp| if temp == result { value } else { temp }, Clone::clone);
+        }
+        for block in &mut function.blocks {
+            if let Terminator::Return(temp) = &mut block.terminator {
+                if *temp == result {
+                    *temp = value;
+                }
+            }
+        }
+        return (block, end);
+    }
+
+    // The body goes between the block with the call and a new block with the
+    // rest of the caller, the blocks after them move down
+    let returned = Variable::Local(fresh(function, globals, &callee.name, "result"));
+    function.locals.push(returned.name().clone());
+    let moved = callee.blocks.len() + 1;
+    for other in &mut function.blocks {
+        if let Terminator::Jump(target) = &mut other.terminator {
+            if *target > block {
+                *target += moved;
+            }
+        }
+    }
+
+    let start = block + 1;
+    let rest = start + callee.blocks.len();
+    let mut after = std::mem::take(&mut function.blocks[block].instructions);
+    let mut before: Vec<Instruction> = after.drain(..=index).collect();
+    before.pop();
+    before.extend(instructions);
+
+    let mut blocks = vec![Block { instructions: before, terminator: Terminator::Jump(start) }];
+    for body in &callee.blocks {
+        let mut instructions: Vec<Instruction> =
+            body.instructions.iter().map(|instruction| rename(instruction, temp, variable)).collect();
+        let terminator = match body.terminator {
+            Terminator::Return(value) => {
+                instructions.push(Instruction::Store(returned.clone(), temp(value)));
+                Terminator::Jump(rest)
+            }
+            Terminator::Jump(target) => Terminator::Jump(start + target),
+        };
+        blocks.push(Block { instructions, terminator });
+    }
+    after.insert(0, Instruction::Load(result, returned));
+    let terminator = function.blocks[block].terminator.clone();
+    blocks.push(Block { instructions: after, terminator });
+
+    function.blocks.splice(block..=block, blocks);
+    (rest, 1)
+}
+
+// A name for a local of the callee that nothing else in the caller uses
+fn fresh(function: &Function, globals: &[Identifier], callee: &str, name: &str) -> Identifier {
+    let taken = |name: &Identifier| {
+        function.params.contains(name) || function.locals.contains(name) || globals.contains(name)
+    };
+    let mut local = format!("{callee}_{name}");
+    let mut suffix = 1;
+    while taken(&local) {
+        local = format!("{callee}_{name}_{suffix}");
+        suffix += 1;
+    }
+    local
+}
+
+fn rename(instruction: &Instruction, temp: impl Fn(Temp) -> Temp, variable: impl Fn(&Variable) -> Variable) -> Instruction {
+    let temps = |temps: &[Temp]| temps.iter().map(|t| temp(*t)).collect();
+    match instruction {
+        Instruction::Const(to, value) => Instruction::Const(temp(*to), value.clone()),
+        Instruction::Load(to, from) => Instruction::Load(temp(*to), variable(from)),
+        Instruction::Store(to, from) => Instruction::Store(variable(to), temp(*from)),
+        Instruction::Binary(to, operator, left, right) => {
+            Instruction::Binary(temp(*to), operator.clone(), temp(*left), temp(*right))
+        }
+        Instruction::Not(to, value) => Instruction::Not(temp(*to), temp(*value)),
+        Instruction::Call(to, name, args) => Instruction::Call(temp(*to), name.clone(), temps(args)),
+        Instruction::Println(to, args) => Instruction::Println(temp(*to), temps(args)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Span;
+
+    fn inline_source(source: &str) -> Module {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        inline(&mut module);
+        module
+    }
+
+    // Args are stored into the params after all of them are evaluated
+    #[test]
+    fn evaluates_args_in_order() {
+        let module = inline_source(
+            "
+            proc second(a, b) {
+                return b
+            }
+            println(second(println(1), println(2)))
+            ",
+        );
+        assert!(module.procs.is_empty());
+        assert_eq!(
+            module.entry.to_string(),
+            "proc <top level>() locals(second_a, second_b)
+b0:
+    t0 = 1
+    t1 = println(t0)
+    t2 = 2
+    t3 = println(t2)
+    second_a = t1
+    second_b = t3
+    t7 = second_b
+    t5 = println(t7)
+    t6 = null
+    return t6
+"
+        );
+    }
+
+    // Code after the return is unreachable, so the body has one block and
+    // the rest of the caller follows it
+    #[test]
+    fn inlines_bodies_with_an_early_return() {
+        let module = inline_source(
+            "
+            proc first(x) {
+                println(x)
+                return x
+                println(\"never\")
+            }
+            var y = first(1)
+            println(y)
+            ",
+        );
+        assert!(module.procs.is_empty());
+        assert_eq!(
+            module.entry.to_string(),
+            "proc <top level>() locals(first_x)
+b0:
+    t0 = 1
+    first_x = t0
+    t5 = first_x
+    t6 = println(t5)
+    t7 = first_x
+    @y = t7
+    t2 = @y
+    t3 = println(t2)
+    t4 = null
+    return t4
+"
+        );
+    }
+
+    #[test]
+    fn inlines_procs_up_to_the_size_limit() {
+        let mut module = Module::from_ast(&crate::parse(
+            "
+            proc small() {
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+            }
+            proc big() {
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1)
+                println(1, 2)
+            }
+            small()
+            big()
+            "
+            .to_string(),
+        ))
+        .unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        let sizes: Vec<usize> = module.procs.iter().map(size).collect();
+        assert_eq!(sizes, [SIZE_LIMIT, SIZE_LIMIT + 1]);
+
+        inline(&mut module);
+        let names: Vec<&str> = module.procs.iter().map(|proc| proc.name.as_str()).collect();
+        assert_eq!(names, ["big"]);
+        assert_eq!(callees(&module.entry), ["big"]);
+    }
+
+    // A proc that only calls a recursive one is still inlined
+    #[test]
+    fn never_inlines_recursive_procs() {
+        let module = inline_source(
+            "
+            proc down(n) {
+                return down(n)
+            }
+            proc ping() {
+                return pong()
+            }
+            proc pong() {
+                return ping()
+            }
+            proc wrapper() {
+                return down(1)
+            }
+            wrapper()
+            ping()
+            ",
+        );
+        let names: Vec<&str> = module.procs.iter().map(|proc| proc.name.as_str()).collect();
+        assert_eq!(names, ["down", "ping", "pong"]);
+        assert_eq!(callees(&module.entry), ["down", "ping"]);
+    }
+
+    // Source programs have no branches, so their procs always end up as a
+    // single block and only hand-built IR reaches this path
+    #[test]
+    fn inlines_bodies_with_several_blocks() {
+        let origins = |proc: &str, temps| {
+            vec![Origin { proc: proc.to_string(), span: Span { line: 1, column: 1 } }; temps]
+        };
+        let callee = Function {
+            name: "show".to_string(),
+            params: vec!["x".to_string()],
+            locals: vec![],
+            blocks: vec![
+                Block {
+                    instructions: vec![Instruction::Load(0, Variable::Local("x".to_string()))],
+                    terminator: Terminator::Jump(1),
+                },
+                Block { instructions: vec![Instruction::Println(1, vec![0])], terminator: Terminator::Return(0) },
+            ],
+            temps: 2,
+            origins: origins("show", 2),
+        };
+        let entry = Function {
+            name: String::new(),
+            params: vec![],
+            locals: vec![],
+            blocks: vec![
+                Block {
+                    instructions: vec![
+                        Instruction::Const(0, Value::Int(1)),
+                        Instruction::Call(1, "show".to_string(), vec![0]),
+                        Instruction::Println(2, vec![1]),
+                    ],
+                    terminator: Terminator::Jump(1),
+                },
+                Block { instructions: vec![Instruction::Const(3, Value::Null)], terminator: Terminator::Return(3) },
+            ],
+            temps: 4,
+            origins: origins("", 4),
+        };
+        let mut module = Module { globals: vec![], procs: vec![callee], entry };
+
+        inline(&mut module);
+        assert!(module.procs.is_empty());
+        assert_eq!(
+            module.entry.to_string(),
+            "proc <top level>() locals(show_x, show_result)
+b0:
+    t0 = 1
+    show_x = t0
+    jump b1
+b1:
+    t4 = show_x
+    jump b2
+b2:
+    t5 = println(t4)
+    show_result = t4
+    jump b3
+b3:
+    t1 = show_result
+    t2 = println(t1)
+    jump b4
+b4:
+    t3 = null
+    return t3
+"
+        );
+        assert_eq!(module.entry.temps, 6);
+        assert_eq!(module.entry.origins.len(), 6);
+    }
+}
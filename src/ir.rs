@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+
+// Builtin that backends lower to their own printing instead of a call
+pub const PRINTLN: &str = "println";
+
+pub type Temp = usize;
+pub type BlockId = usize;
+
+// Values known at compile time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Variable {
+    Local(Identifier), // Argument or local of the current proc
+    Global(Identifier),
+}
+
+impl Variable {
+    pub fn name(&self) -> &Identifier {
+        match self {
+            Variable::Local(name) | Variable::Global(name) => name,
+        }
+    }
+}
+
+// Three-address code. Every temp is assigned by exactly one instruction.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Const(Temp, Value),
+    Load(Temp, Variable),
+    Store(Variable, Temp),
+    Binary(Temp, BinaryOperator, Temp, Temp), // to = left op right
+    Not(Temp, Temp),
+    Call(Temp, Identifier, Vec<Temp>),
+    Println(Temp, Vec<Temp>), // Evaluates to null
+}
+
+impl Instruction {
+    pub fn defines(&self) -> Option<Temp> {
+        match self {
+            | Instruction::Const(temp, _)
+            | Instruction::Load(temp, _)
+            | Instruction::Binary(temp, ..)
+            | Instruction::Not(temp, _)
+            | Instruction::Call(temp, ..)
+            | Instruction::Println(temp, _) => Some(*temp),
+            Instruction::Store(..) => None,
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Temp> {
+        match self {
+            Instruction::Const(..) | Instruction::Load(..) => vec![],
+            Instruction::Store(_, temp) | Instruction::Not(_, temp) => vec![*temp],
+            Instruction::Binary(_, _, left, right) => vec![*left, *right],
+            Instruction::Call(_, _, args) | Instruction::Println(_, args) => args.clone(),
+        }
+    }
+}
+
+// How control leaves a block
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Return(Temp),
+    Jump(BlockId),
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<Temp> {
+        match self {
+            Terminator::Return(temp) => vec![*temp],
+            Terminator::Jump(_) => vec![],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Return(_) => vec![],
+            Terminator::Jump(block) => vec![*block],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+// Where the code that assigns a temp was written. Inlined temps keep the
+// origin they had in their own proc.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub proc: Identifier, // Empty for the top level
+    pub span: Span,
+}
+
+impl Origin {
+    // Where the code is, as diagnostics word it
+    pub fn context(&self) -> String {
+        match self.proc.as_str() {
+            "" => "at the top level".to_string(),
+            name => format!("in proc {name}"),
+        }
+    }
+}
+
+// A proc, or the top level code. Block 0 is the entry and jumps only go
+// forward, to blocks later in the list.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub locals: Vec<Identifier>, // Vars and consts that aren't params
+    pub blocks: Vec<Block>,
+    pub temps: usize,
+    pub origins: Vec<Origin>, // Of each temp
+}
+
+impl Function {
+    // The instruction that assigns each temp
+    pub fn definitions(&self) -> HashMap<Temp, &Instruction> {
+        let mut definitions = HashMap::new();
+        for instruction in self.blocks.iter().flat_map(|block| &block.instructions) {
+            if let Some(temp) = instruction.defines() {
+                definitions.insert(temp, instruction);
+            }
+        }
+        definitions
+    }
+
+    // Number of times each temp is used
+    pub fn use_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.temps];
+        for block in &self.blocks {
+            let uses = block.instructions.iter().flat_map(Instruction::uses);
+            for temp in uses.chain(block.terminator.uses()) {
+                counts[temp] += 1;
+            }
+        }
+        counts
+    }
+
+    // Blocks that are jump targets
+    pub fn targets(&self) -> HashSet<BlockId> {
+        self.blocks.iter().flat_map(|block| block.terminator.successors()).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub globals: Vec<Identifier>,
+    pub procs: Vec<Function>,
+    pub entry: Function, // Top level code, which calls main if there is one
+}
+
+impl Module {
+    // Procs are all hoisted to the top level, nested ones can only see
+    // globals, their arguments and their own locals. Names that aren't
+    // declared where they are used and procs declared twice are reported.
+    pub fn from_ast(root: &Program) -> Result<Self, Vec<Diagnostic>> {
+        let mut procs = vec![];
+        collect_procs(root, &mut procs);
+        let mut diagnostics = duplicate_procs(&procs);
+        let proc_names: HashSet<Identifier> = procs.iter().map(|(proc, _)| proc.identifier.clone()).collect();
+
+        let globals = declarations(root);
+        let global_scope: HashMap<Identifier, Variable> = globals
+            .iter()
+            .map(|name| (name.clone(), Variable::Global(name.clone())))
+            .collect();
+
+        let mut functions = vec![];
+        for (proc, span) in &procs {
+            let mut scope = global_scope.clone();
+            let locals: Vec<Identifier> = declarations(&proc.block)
+                .into_iter()
+                .filter(|local| !proc.proc_args.contains(local))
+                .collect();
+            for name in proc.proc_args.iter().chain(&locals) {
+                scope.insert(name.clone(), Variable::Local(name.clone()));
+            }
+
+            let mut builder = Builder::new(proc.identifier.clone(), &scope, &proc_names);
+            builder.function.params = proc.proc_args.clone();
+            builder.function.locals = locals;
+            builder.block(&proc.block);
+            // Falling off the end returns null
+            builder.span = *span;
+            let null = builder.constant(Value::Null);
+            builder.terminate(Terminator::Return(null));
+            functions.push(builder.finish(&mut diagnostics));
+        }
+
+        let mut builder = Builder::new(String::new(), &global_scope, &proc_names);
+        builder.block(root);
+        let status = if proc_names.contains("main") {
+            let temp = builder.temp();
+            builder.emit(Instruction::Call(temp, "main".to_string(), vec![]));
+            temp
+        } else {
+            builder.constant(Value::Null)
+        };
+        builder.terminate(Terminator::Return(status));
+        let entry = builder.finish(&mut diagnostics);
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(Module {
+            globals,
+            procs: functions,
+            entry,
+        })
+    }
+}
+
+// Every proc in the block and the blocks of its procs, with the span of its
+// declaration
+pub fn collect_procs(block: &Program, procs: &mut Vec<(Proc, Span)>) {
+    for (item, span) in block.items.iter().zip(&block.spans) {
+        if let Item::Declaration(Declaration::Proc(proc)) = item {
+            procs.push((proc.clone(), *span));
+            collect_procs(&proc.block, procs);
+        }
+    }
+}
+
+// Procs are hoisted, so each name can only be declared once in the program
+pub fn duplicate_procs(procs: &[(Proc, Span)]) -> Vec<Diagnostic> {
+    let mut seen = HashSet::new();
+    procs
+        .iter()
+        .filter(|(proc, _)| !seen.insert(&proc.identifier))
+        .map(|(proc, span)| Diagnostic::error(format!("proc {} is already declared", proc.identifier)).at(*span))
+        .collect()
+}
+
+// Vars and consts declared in the block, in order
+pub fn declarations(block: &Program) -> Vec<Identifier> {
+    let mut names = vec![];
+    for item in &block.items {
+        if let Item::Declaration(Declaration::Var(Var { identifier, .. }) | Declaration::Const(Const { identifier, .. })) = item {
+            if !names.contains(identifier) {
+                names.push(identifier.clone());
+            }
+        }
+    }
+    names
+}
+
+struct Builder<'a> {
+    function: Function,
+    instructions: Vec<Instruction>, // Of the block being built
+    scope: &'a HashMap<Identifier, Variable>,
+    procs: &'a HashSet<Identifier>,
+    span: Span, // Of the item being built
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(name: Identifier, scope: &'a HashMap<Identifier, Variable>, procs: &'a HashSet<Identifier>) -> Self {
+        Builder {
+            function: Function {
+                name,
+                params: vec![],
+                locals: vec![],
+                blocks: vec![],
+                temps: 0,
+                origins: vec![],
+            },
+            instructions: vec![],
+            scope,
+            procs,
+            span: Span::default(),
+            diagnostics: vec![],
+        }
+    }
+
+    fn finish(self, diagnostics: &mut Vec<Diagnostic>) -> Function {
+        diagnostics.extend(self.diagnostics);
+        self.function
+    }
+
+    fn error(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic::error(message).at(self.span));
+    }
+
+    fn temp(&mut self) -> Temp {
+        let origin = Origin { proc: self.function.name.clone(), span: self.span };
+        self.function.origins.push(origin);
+        self.function.temps += 1;
+        self.function.temps - 1
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn constant(&mut self, value: Value) -> Temp {
+        let temp = self.temp();
+        self.emit(Instruction::Const(temp, value));
+        temp
+    }
+
+    // Closes the current block, anything emitted after it goes in a new one
+    fn terminate(&mut self, terminator: Terminator) {
+        let instructions = std::mem::take(&mut self.instructions);
+        self.function.blocks.push(Block { instructions, terminator });
+    }
+
+    fn variable(&mut self, identifier: &Identifier) -> Option<Variable> {
+        let variable = self.scope.get(identifier).cloned();
+        if variable.is_none() {
+            self.error(format!("undefined variable {identifier}"));
+        }
+        variable
+    }
+
+    fn block(&mut self, block: &Program) {
+        for (item, span) in block.items.iter().zip(&block.spans) {
+            self.span = *span;
+            match item {
+                Item::Assignment(Assignment { identifier, expr })
+                | Item::Declaration(Declaration::Var(Var { identifier, expr, .. }))
+                | Item::Declaration(Declaration::Const(Const { identifier, expr, .. })) => {
+                    let variable = self.variable(identifier);
+                    let value = self.expression(expr);
+                    if let Some(variable) = variable {
+                        self.emit(Instruction::Store(variable, value));
+                    }
+                }
+                Item::Declaration(Declaration::Proc(_)) => {}
+                Item::Expression(expr) => {
+                    self.expression(expr);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Temp {
+        match expression {
+            Expression::Unary(unary) => self.unary(unary),
+            Expression::Binary(Binary { left, operator, right }) => {
+                let left = self.unary(left);
+                let right = self.expression(right);
+                let temp = self.temp();
+                self.emit(Instruction::Binary(temp, operator.clone(), left, right));
+                temp
+            }
+        }
+    }
+
+    fn unary(&mut self, unary: &Unary) -> Temp {
+        match unary {
+            Unary::UnaryOperation { operator: UnaryOperator::Negate, unary } => {
+                let value = self.unary(unary);
+                let temp = self.temp();
+                self.emit(Instruction::Not(temp, value));
+                temp
+            }
+            Unary::Call(Call::CallLiteral { identifier, call_args }) => {
+                let args: Vec<Temp> = call_args.iter().map(|arg| self.expression(arg)).collect();
+                if identifier != PRINTLN && !self.procs.contains(identifier) {
+                    self.error(format!("call to undefined proc {identifier}"));
+                    return self.constant(Value::Null);
+                }
+                let temp = self.temp();
+                if identifier == PRINTLN {
+                    self.emit(Instruction::Println(temp, args));
+                } else {
+                    self.emit(Instruction::Call(temp, identifier.clone(), args));
+                }
+                temp
+            }
+            Unary::Call(Call::Primary(primary)) => self.primary(primary),
+        }
+    }
+
+    fn primary(&mut self, primary: &Primary) -> Temp {
+        match primary {
+            Primary::True => self.constant(Value::Bool(true)),
+            Primary::False => self.constant(Value::Bool(false)),
+            Primary::Null => self.constant(Value::Null),
+            Primary::Int(int) => self.constant(Value::Int(*int)),
+            Primary::Float(float) => self.constant(Value::Float(*float)),
+            Primary::String(string) => self.constant(Value::String(string.clone())),
+            // An undefined variable has already been reported, the null only
+            // keeps the rest of the code building
+            Primary::Identifier(identifier) => {
+                let Some(variable) = self.variable(identifier) else {
+                    return self.constant(Value::Null);
+                };
+                let temp = self.temp();
+                self.emit(Instruction::Load(temp, variable));
+                temp
+            }
+            // The rest of the expression is unreachable, it goes in a block
+            // of its own with a placeholder for the value of the return
+            Primary::Return(expression) => {
+                let value = self.expression(expression);
+                self.terminate(Terminator::Return(value));
+                self.constant(Value::Null)
+            }
+            Primary::Expression(expression) => self.expression(expression),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(bool) => write!(f, "{bool}"),
+            Value::Int(int) => write!(f, "{int}"),
+            Value::Float(float) => write!(f, "{float:?}"),
+            Value::String(string) => write!(f, "{string:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variable::Local(name) => write!(f, "{name}"),
+            Variable::Global(name) => write!(f, "@{name}"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let temps = |temps: &[Temp]| temps.iter().map(|temp| format!("t{temp}")).collect::<Vec<_>>().join(", ");
+        match self {
+            Instruction::Const(temp, value) => write!(f, "t{temp} = {value}"),
+            Instruction::Load(temp, variable) => write!(f, "t{temp} = {variable}"),
+            Instruction::Store(variable, temp) => write!(f, "{variable} = t{temp}"),
+            Instruction::Binary(temp, operator, left, right) => {
+                let operator = match operator {
+                    BinaryOperator::Plus => "+",
+                    BinaryOperator::Minus => "-",
+                    BinaryOperator::Multiply => "*",
+                    BinaryOperator::Divide => "/",
+                };
+                write!(f, "t{temp} = t{left} {operator} t{right}")
+            }
+            Instruction::Not(temp, value) => write!(f, "t{temp} = !t{value}"),
+            Instruction::Call(temp, name, args) => write!(f, "t{temp} = call {name}({})", temps(args)),
+            Instruction::Println(temp, args) => write!(f, "t{temp} = println({})", temps(args)),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.name.is_empty() { "<top level>" } else { &self.name };
+        writeln!(f, "proc {name}({}) locals({})", self.params.join(", "), self.locals.join(", "))?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{id}:")?;
+            for instruction in &block.instructions {
+                writeln!(f, "    {instruction}")?;
+            }
+            match block.terminator {
+                Terminator::Return(temp) => writeln!(f, "    return t{temp}")?,
+                Terminator::Jump(target) => writeln!(f, "    jump b{target}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.globals.is_empty() {
+            writeln!(f, "globals({})", self.globals.join(", "))?;
+        }
+        write!(f, "{}", self.entry)?;
+        for proc in &self.procs {
+            write!(f, "\n{proc}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::{BinaryOperator, Identifier};
+use crate::ir::*;
+
+// Names that can't be used as JavaScript identifiers, they get a trailing _
+const RESERVED: [&str; 44] = [
+    "arguments", "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default", "delete",
+    "do", "else", "enum", "eval", "export", "extends", "false", "finally", "for", "function", "if", "implements",
+    "import", "in", "instanceof", "interface", "let", "new", "null", "package", "private", "protected", "public",
+    "return", "static", "super", "switch", "this", "throw", "try", "typeof", "void", "while",
+];
+
+// Value model the generated code is built on. Runtime names start with $,
+// which can't appear in identifiers of the language.
+const RUNTIME: &str = r#""use strict";
+
+// Integers are BigInts wrapped to 64 bits like i64, floats are numbers
+function $arithmetic(operator, a, b) {
+    const isNumber = (value) => typeof value === "bigint" || typeof value === "number";
+    if (!isNumber(a) || !isNumber(b)) {
+        throw new TypeError(`Invalid operands to ${operator}`);
+    }
+
+    if (typeof a === "bigint" && typeof b === "bigint") {
+        switch (operator) {
+            case "+": return BigInt.asIntN(64, a + b);
+            case "-": return BigInt.asIntN(64, a - b);
+            case "*": return BigInt.asIntN(64, a * b);
+            default:
+                if (b === 0n) {
+                    throw new RangeError("Division by zero");
+                }
+                return BigInt.asIntN(64, a / b);
+        }
+    }
+
+    a = Number(a);
+    b = Number(b);
+    switch (operator) {
+        case "+": return a + b;
+        case "-": return a - b;
+        case "*": return a * b;
+        default: return a / b;
+    }
+}
+
+function $add(a, b) {
+    if (typeof a === "string" && typeof b === "string") {
+        return a + b;
+    }
+    return $arithmetic("+", a, b);
+}
+
+const $sub = (a, b) => $arithmetic("-", a, b);
+const $mul = (a, b) => $arithmetic("*", a, b);
+const $div = (a, b) => $arithmetic("/", a, b);
+
+function $not(value) {
+    return value === null || value === false || value === 0n || value === 0 || value === "";
+}
+
+function $println(...values) {
+    console.log(values.map((value) => String(value)).join(" "));
+    return null;
+}
+"#;
+
+// Compiles a program to ES2020. Procs become functions, the top level code
+// becomes the function $start, which calls main, and vars and consts become
+// let bindings. Consts are let too because the language lets them be
+// assigned again, which a JavaScript const would throw on. Names are kept as
+// they are unless they are reserved in JavaScript.
+//
+// IR temps become consts, or lets declared at the top of the function if a
+// later block uses them. Constants are written where they are used. Jumps
+// only go forward, so every jump target gets a labeled block that ends right
+// before it and jumps break out of it. The value the top level returns is
+// dropped, there is no exit status in a browser.
+pub fn generate_code(module: &Module) -> String {
+    let mut output = String::from(RUNTIME);
+    output += "\n";
+
+    if !module.globals.is_empty() {
+        let globals: Vec<String> = module.globals.iter().map(|name| format!("{} = null", name_of(name))).collect();
+        output += &format!("let {};\n", globals.join(", "));
+    }
+
+    for proc in &module.procs {
+        let params: Vec<String> = proc.params.iter().map(name_of).collect();
+        output += &format!("\nfunction {}({}) {{\n", name_of(&proc.name), params.join(", "));
+        output += &Writer::new(proc).function(proc);
+        output += "}\n";
+    }
+
+    output += "\nfunction $start() {\n";
+    output += &Writer::new(&module.entry).function(&module.entry);
+    output += "}\n";
+    output += "\n$start();\n";
+    output
+}
+
+fn name_of(identifier: &Identifier) -> String {
+    if RESERVED.contains(&identifier.as_str()) {
+        format!("{identifier}_")
+    } else {
+        identifier.clone()
+    }
+}
+
+struct Writer<'a> {
+    body: String,
+    indent: usize,
+    definitions: HashMap<Temp, &'a Instruction>,
+    uses: Vec<usize>,
+    shared: HashSet<Temp>, // Used in a later block than the one defining them
+}
+
+impl<'a> Writer<'a> {
+    fn new(function: &'a Function) -> Self {
+        let mut defined_in = HashMap::new();
+        let mut shared = HashSet::new();
+        for (id, block) in function.blocks.iter().enumerate() {
+            for instruction in &block.instructions {
+                for temp in instruction.uses() {
+                    if defined_in.get(&temp).is_some_and(|defined| *defined != id) {
+                        shared.insert(temp);
+                    }
+                }
+                if let Some(temp) = instruction.defines() {
+                    defined_in.insert(temp, id);
+                }
+            }
+            for temp in block.terminator.uses() {
+                if defined_in.get(&temp).is_some_and(|defined| *defined != id) {
+                    shared.insert(temp);
+                }
+            }
+        }
+
+        Writer {
+            body: String::new(),
+            indent: 1,
+            definitions: function.definitions(),
+            uses: function.use_counts(),
+            shared,
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.body += &"    ".repeat(self.indent);
+        self.body += line;
+        self.body += "\n";
+    }
+
+    fn function(mut self, function: &Function) -> String {
+        let mut shared: Vec<&Temp> = self.shared.iter().filter(|temp| !self.is_constant(**temp)).collect();
+        shared.sort();
+        let mut declarations: Vec<String> = function.locals.iter().map(|name| format!("{} = null", name_of(name))).collect();
+        declarations.extend(shared.iter().map(|temp| format!("$t{temp}")));
+        if !declarations.is_empty() {
+            self.line(&format!("let {};", declarations.join(", ")));
+        }
+
+        // A jump to the next block falls through and needs no label
+        let mut labels: Vec<BlockId> = function
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(id, block)| match block.terminator {
+                Terminator::Jump(target) if target != id + 1 => Some(target),
+                _ => None,
+            })
+            .collect();
+        labels.sort();
+        labels.dedup();
+        for label in labels.iter().rev() {
+            self.line(&format!("$b{label}: {{"));
+            self.indent += 1;
+        }
+
+        for (id, block) in function.blocks.iter().enumerate() {
+            if labels.contains(&id) {
+                self.indent -= 1;
+                self.line("}");
+            }
+
+            for instruction in &block.instructions {
+                self.instruction(instruction);
+            }
+
+            match block.terminator {
+                Terminator::Return(temp) => {
+                    let value = self.temp(temp);
+                    self.line(&format!("return {value};"));
+                }
+                Terminator::Jump(target) if target == id + 1 => {}
+                Terminator::Jump(target) => self.line(&format!("break $b{target};")),
+            }
+        }
+        self.body
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        let value = match instruction {
+            Instruction::Const(..) => return,
+            Instruction::Store(variable, temp) => {
+                let value = self.temp(*temp);
+                self.line(&format!("{} = {value};", name_of(variable.name())));
+                return;
+            }
+            Instruction::Load(_, variable) => name_of(variable.name()),
+            Instruction::Binary(_, operator, left, right) => {
+                let function = match operator {
+                    BinaryOperator::Plus => "$add",
+                    BinaryOperator::Minus => "$sub",
+                    BinaryOperator::Multiply => "$mul",
+                    BinaryOperator::Divide => "$div",
+                };
+                format!("{function}({}, {})", self.temp(*left), self.temp(*right))
+            }
+            Instruction::Not(_, value) => format!("$not({})", self.temp(*value)),
+            Instruction::Call(_, identifier, args) => format!("{}({})", name_of(identifier), self.temps(args)),
+            Instruction::Println(_, args) => format!("$println({})", self.temps(args)),
+        };
+
+        match instruction.defines() {
+            Some(temp) if self.uses[temp] > 0 && self.shared.contains(&temp) => {
+                self.line(&format!("$t{temp} = {value};"));
+            }
+            Some(temp) if self.uses[temp] > 0 => self.line(&format!("const $t{temp} = {value};")),
+            _ => self.line(&format!("{value};")),
+        }
+    }
+
+    fn is_constant(&self, temp: Temp) -> bool {
+        matches!(self.definitions.get(&temp), Some(Instruction::Const(..)))
+    }
+
+    fn temps(&self, temps: &[Temp]) -> String {
+        temps.iter().map(|temp| self.temp(*temp)).collect::<Vec<_>>().join(", ")
+    }
+
+    // The JavaScript expression holding the temp's value
+    fn temp(&self, temp: Temp) -> String {
+        match self.definitions.get(&temp) {
+            Some(Instruction::Const(_, value)) => constant(value),
+            _ => format!("$t{temp}"),
+        }
+    }
+}
+
+fn constant(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(bool) => bool.to_string(),
+        Value::Int(int) => format!("{int}n"),
+        // Folding can make infinite floats, {:?} writes them as inf
+        Value::Float(float) if float.is_infinite() => {
+            format!("{}Infinity", if *float < 0.0 { "-" } else { "" })
+        }
+        Value::Float(float) => format!("{float:?}"),
+        Value::String(string) => quote(string),
+    }
+}
+
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for char in string.chars() {
+        match char {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\t' => quoted += "\\t",
+            ' '..='~' => quoted.push(char),
+            _ => quoted += &format!("\\u{{{:x}}}", char as u32),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Span;
+
+    // No program lowers to jumps yet, so the IR is written by hand
+    #[test]
+    fn jumps_break_out_of_labeled_blocks() {
+        let origin = Origin { proc: String::new(), span: Span::default() };
+        let entry = Function {
+            name: String::new(),
+            params: vec![],
+            locals: vec!["x".to_string()],
+            blocks: vec![
+                Block {
+                    instructions: vec![Instruction::Load(0, Variable::Local("x".to_string()))],
+                    terminator: Terminator::Jump(2),
+                },
+                Block { instructions: vec![], terminator: Terminator::Return(0) },
+                Block { instructions: vec![Instruction::Println(1, vec![0])], terminator: Terminator::Return(1) },
+            ],
+            temps: 2,
+            origins: vec![origin.clone(), origin],
+        };
+        let module = Module { globals: vec![], procs: vec![], entry };
+
+        let code = generate_code(&module);
+        let start = &code[code.find("function $start").unwrap()..];
+        assert_eq!(
+            start,
+            "function $start() {
+    let x = null, $t0;
+    $b2: {
+        $t0 = x;
+        break $b2;
+        return $t0;
+    }
+    const $t1 = $println($t0);
+    return $t1;
+}
+
+$start();
+"
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use crate::token::Token;
+use crate::token::{Span, Token};
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 fn is_numeric(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha_numeric(c: char) -> bool {
@@ -16,8 +16,10 @@ fn is_alpha_numeric(c: char) -> bool {
 pub struct Lexer {
     source: Box<[u8]>,
     pub tokens: Vec<Token>,
+    pub spans: Vec<Span>, // Of each token
     current_idx: usize,
     current_line: usize,
+    line_start: usize,
 }
 
 impl Lexer {
@@ -25,8 +27,10 @@ impl Lexer {
         Lexer {
             source: source.as_bytes().to_owned().into_boxed_slice(),
             tokens: vec![],
+            spans: vec![],
             current_idx: 0,
             current_line: 0,
+            line_start: 0,
         }
     }
 
@@ -39,14 +43,24 @@ impl Lexer {
 
         match c {
             ' ' | '\r' | '\t' => {}
-            '\n' => self.current_line += 1,
+            '\n' => {
+                self.current_line += 1;
+                self.line_start = self.current_idx + 1;
+            }
             '(' => self.tokens.push(Token::LeftParen),
             ')' => self.tokens.push(Token::RightParen),
             '{' => self.tokens.push(Token::LeftBrace),
             '}' => self.tokens.push(Token::RightBrace),
             ',' => self.tokens.push(Token::Comma),
+            ':' => self.tokens.push(Token::Colon),
             '.' => self.tokens.push(Token::Dot),
-            '-' => self.tokens.push(Token::Minus),
+            '-' => match self.source.get(self.current_idx + 1) {
+                Some(b'>') => {
+                    self.current_idx += 1;
+                    self.tokens.push(Token::Arrow);
+                }
+                _ => self.tokens.push(Token::Minus),
+            },
             '+' => self.tokens.push(Token::Plus),
             '*' => self.tokens.push(Token::Star),
             ';' => self.tokens.push(Token::Semicolon),
@@ -118,7 +132,15 @@ impl Lexer {
 
     pub fn lex(&mut self) {
         while !self.is_at_end() {
+            let span = Span {
+                line: self.current_line + 1,
+                column: self.current_idx - self.line_start + 1,
+            };
+            let count = self.tokens.len();
             self.lex_token();
+            if self.tokens.len() > count {
+                self.spans.push(span);
+            }
         }
     }
 
@@ -126,7 +148,7 @@ impl Lexer {
         if self.is_at_end() {
             return '\0';
         }
-        return self.source[self.current_idx] as char;
+        self.source[self.current_idx] as char
     }
 
     fn lex_string(&mut self) -> Token {
@@ -185,14 +207,14 @@ impl Lexer {
             return Token::Float(
                 number_literal
                     .parse::<f64>()
-                    .expect(format!("Could not parse number {number_literal}").as_str()),
+                    .unwrap_or_else(|_| panic!("Could not parse number {number_literal}")),
             );
         }
 
         Token::Int(
             number_literal
                 .parse::<i64>()
-                .expect(format!("Could not parse number {number_literal}").as_str()),
+                .unwrap_or_else(|_| panic!("Could not parse number {number_literal}")),
         )
     }
 }
@@ -1,3 +1,4 @@
+use std::path::Path;
 use lexer::Lexer;
 
 mod token;
@@ -5,15 +6,295 @@ mod lexer;
 mod parser;
 mod ast;
 mod vm;
+mod assembler;
+mod bytecode;
+mod verifier;
+mod debugger;
+mod trace;
+mod profiler;
+mod peephole;
+mod register_allocator;
 mod code_generator;
+mod x86_64;
+mod wasm;
+mod javascript;
+mod ir;
+mod diagnostic;
+mod constant_folding;
+mod dead_code;
+mod inlining;
+mod type_checker;
+mod value_types;
 
-fn main() {
-    let file_path = "test.txt";
-    let content = std::fs::read_to_string(&file_path).expect(format!("Unable to read file {file_path}").as_str());
+fn assemble_file(file_path: &str) -> vm::VM {
+    let source = std::fs::read_to_string(file_path)
+        .unwrap_or_else(|_| panic!("Unable to read file {file_path}"));
+    match assembler::assemble(&source) {
+        Ok(vm) => vm,
+        Err(error) => {
+            eprintln!("{file_path}: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_file(file_path: &str) -> ast::Program {
+    let content = std::fs::read_to_string(file_path)
+        .unwrap_or_else(|_| panic!("Unable to read file {file_path}"));
+    parse(content)
+}
 
-    let mut lexer = Lexer::new(content.clone());
+fn parse(content: String) -> ast::Program {
+    let mut lexer = Lexer::new(content);
     lexer.lex();
-    let mut parser = parser::Parser::new(lexer.tokens);
+    let mut parser = parser::Parser::new(lexer.tokens, lexer.spans);
     parser.parse();
-    println!("{:#?}", parser.root);
+    parser.root
+}
+
+fn report(file_path: &str, diagnostics: &[diagnostic::Diagnostic]) {
+    for diagnostic in diagnostics {
+        match diagnostic.span {
+            Some(span) => eprintln!("{file_path}:{span}: {diagnostic}"),
+            None => eprintln!("{file_path}: {diagnostic}"),
+        }
+    }
+    if diagnostic::has_errors(diagnostics) {
+        std::process::exit(1);
+    }
+}
+
+// Parses the program, checks its types and removes dead code from it. What
+// was removed is only reported when asked for with -Wdead-code.
+fn parse_and_eliminate(file_path: &str, options: &[String]) -> ast::Program {
+    let mut root = parse_file(file_path);
+    report(file_path, &type_checker::check(&root));
+    let diagnostics = dead_code::eliminate(&mut root);
+    if options.iter().any(|option| option == "-Wdead-code") {
+        report(file_path, &diagnostics);
+    }
+    root
+}
+
+// Lowers the program to IR and runs the optimization passes over it. The
+// diagnostics they report are printed and errors stop the compilation.
+fn compile(file_path: &str, options: &[String]) -> ir::Module {
+    let mut module = match ir::Module::from_ast(&parse_and_eliminate(file_path, options)) {
+        Ok(module) => module,
+        Err(diagnostics) => {
+            report(file_path, &diagnostics);
+            std::process::exit(1);
+        }
+    };
+    dead_code::remove_unreachable_blocks(&mut module);
+    inlining::inline(&mut module);
+    report(file_path, &constant_folding::fold(&mut module));
+    module
+}
+
+// Prints the program compiled to C
+fn compile_c(file_path: &str, options: &[String]) {
+    print!("{}", code_generator::generate_code(&compile(file_path, options)));
+}
+
+// Prints the program compiled to x86-64 assembly
+fn compile_x86_64(file_path: &str, options: &[String]) {
+    match x86_64::generate_code(&compile(file_path, options)) {
+        Ok(code) => print!("{code}"),
+        Err(diagnostics) => report(file_path, &diagnostics),
+    }
+}
+
+// Prints the program compiled to WebAssembly text
+fn compile_wasm(file_path: &str, options: &[String]) {
+    match wasm::generate_code(&compile(file_path, options)) {
+        Ok(code) => print!("{code}"),
+        Err(diagnostics) => report(file_path, &diagnostics),
+    }
+}
+
+// Prints the program compiled to JavaScript
+fn compile_javascript(file_path: &str, options: &[String]) {
+    print!("{}", javascript::generate_code(&compile(file_path, options)));
+}
+
+// Prints the optimized IR the backends are lowered from
+fn print_ir(file_path: &str, options: &[String]) {
+    print!("{}", compile(file_path, options));
+}
+
+// Compiles a source file for the VM, assembles a .asm file or loads a
+// bytecode file, which is verified as it is read
+fn load_program(file_path: &str, options: &[String]) -> vm::VM {
+    if file_path.ends_with(".fibc") {
+        return bytecode::read_file(Path::new(file_path)).unwrap_or_else(|error| {
+            eprintln!("{file_path}: {error}");
+            std::process::exit(1);
+        });
+    }
+
+    let vm = if file_path.ends_with(".asm") {
+        assemble_file(file_path)
+    } else {
+        match vm::VM::from_ir(&compile(file_path, options)) {
+            Ok(vm) => vm,
+            Err(diagnostics) => {
+                report(file_path, &diagnostics);
+                std::process::exit(1);
+            }
+        }
+    };
+    if let Err(errors) = verifier::verify(&vm) {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        eprintln!("{file_path}: {}", errors.join(", "));
+        std::process::exit(1);
+    }
+    vm
+}
+
+// Limits for a program on the VM from the -max-instructions=N and
+// -max-memory=N options, memory is counted in words
+fn limits(options: &[String]) -> vm::Limits {
+    let mut limits = vm::Limits::default();
+    for option in options {
+        let Some((name, value)) = option.split_once('=') else {
+            continue;
+        };
+        let parse = |value: &str| -> u64 {
+            value.parse().unwrap_or_else(|_| panic!("Expected a number for {name}, got {value}"))
+        };
+        match name {
+            "-max-instructions" => limits.instructions = Some(parse(value)),
+            "-max-memory" => limits.memory = parse(value) as usize,
+            _ => {}
+        }
+    }
+    limits
+}
+
+// Runs the program on the VM and exits with the value main returned
+fn run(mut vm: vm::VM, options: &[String]) {
+    vm.set_limits(limits(options));
+    match vm.run() {
+        Ok(vm::ExitStatus::Returned(status)) => std::process::exit(status as i32),
+        Ok(_) => {}
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Writes the program compiled for the VM to a bytecode file
+fn build(file_path: &str, output_path: &str, options: &[String]) {
+    let vm = load_program(file_path, options);
+    if let Err(error) = bytecode::write_file(&vm, Path::new(output_path)) {
+        eprintln!("{output_path}: {error}");
+        std::process::exit(1);
+    }
+}
+
+// Runs the program for at most the given number of instructions and saves
+// it to a snapshot if it hasn't finished by then
+fn pause(file_path: &str, instructions: &str, snapshot_path: &str) {
+    let instructions: u64 = instructions
+        .parse()
+        .unwrap_or_else(|_| panic!("Expected a number of instructions, got {instructions}"));
+    let mut vm = load_program(file_path, &[]);
+    let status = vm.run_for(instructions).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(1);
+    });
+    if vm.is_finished() {
+        if let vm::ExitStatus::Returned(status) = status {
+            std::process::exit(status as i32);
+        }
+        return;
+    }
+
+    if let Err(error) = bytecode::write_snapshot(&vm, Path::new(snapshot_path)) {
+        eprintln!("{snapshot_path}: {error}");
+        std::process::exit(1);
+    }
+    eprintln!("Paused after {} instructions", vm.executed());
+}
+
+// Resumes a program saved by pause. Limits aren't saved with it, they are
+// given again.
+fn resume(snapshot_path: &str, options: &[String]) {
+    match bytecode::read_snapshot(Path::new(snapshot_path)) {
+        Ok(vm) => run(vm, options),
+        Err(error) => {
+            eprintln!("{snapshot_path}: {error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Prints the VM code for a program
+fn disassemble(file_path: &str, options: &[String]) {
+    print!("{}", assembler::disassemble(&load_program(file_path, options)));
+}
+
+// Runs the program under the interactive debugger
+fn debug(file_path: &str, options: &[String]) {
+    let vm = load_program(file_path, options);
+    let mut debugger = debugger::Debugger::new(vm);
+    debugger
+        .run(&mut std::io::stdin().lock(), &mut std::io::stdout())
+        .expect("Unable to run debugger");
+}
+
+// Runs the program, logging every instruction to stderr
+fn trace(file_path: &str, options: &[String]) {
+    let mut vm = load_program(file_path, options);
+    vm.set_trace(Box::new(std::io::stderr()));
+    if let Err(error) = vm.run() {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+}
+
+// Runs the program and prints where it spent its time, optionally writing
+// folded stacks for flamegraph tools to the path given after the program
+fn profile(file_path: &str, options: &[String]) {
+    let folded_path = options.first().filter(|path| !path.starts_with('-'));
+    let mut vm = load_program(file_path, options);
+    vm.enable_profiling();
+    if let Err(error) = vm.run() {
+        eprintln!("{error}");
+    }
+
+    let profile = vm.take_profile().unwrap();
+    eprint!("{}", profile.report());
+
+    if let Some(folded_path) = folded_path {
+        let mut file = std::fs::File::create(folded_path)
+            .unwrap_or_else(|_| panic!("Unable to create file {folded_path}"));
+        profile.write_folded(&mut file).expect("Unable to write folded stacks");
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, file_path, rest @ ..] = args.as_slice() {
+        match command.as_str() {
+            "c" => return compile_c(file_path, rest),
+            "x86_64" => return compile_x86_64(file_path, rest),
+            "wasm" => return compile_wasm(file_path, rest),
+            "js" => return compile_javascript(file_path, rest),
+            "ir" => return print_ir(file_path, rest),
+            "run" => return run(load_program(file_path, rest), rest),
+            "build" if !rest.is_empty() => return build(file_path, &rest[0], &rest[1..]),
+            "pause" if rest.len() == 2 => return pause(file_path, &rest[0], &rest[1]),
+            "resume" => return resume(file_path, rest),
+            "disasm" => return disassemble(file_path, rest),
+            "debug" => return debug(file_path, rest),
+            "trace" => return trace(file_path, rest),
+            "profile" => return profile(file_path, rest),
+            _ => {}
+        }
+    }
+
+    println!("{:#?}", parse_file("test.txt"));
 }
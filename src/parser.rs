@@ -1,22 +1,28 @@
-use crate::token::Token;
+use crate::token::{Span, Token};
 use crate::ast::*;
 
 #[derive(Debug)]
 pub struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>,
     current_idx: usize,
     pub root: Program,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(tokens: Vec<Token>, spans: Vec<Span>) -> Self {
         Parser {
             tokens,
+            spans,
             current_idx: 0,
-            root: Program { items: vec![] },
+            root: Program { items: vec![], spans: vec![] },
         }
     }
 
+    fn span(&self) -> Span {
+        self.spans.get(self.current_idx).copied().unwrap_or_default()
+    }
+
     fn advance(&mut self) {
         self.current_idx += 1;
     }
@@ -30,8 +36,7 @@ impl Parser {
             return Token::EOF;
         }
 
-        let token = self.tokens[self.current_idx + n].clone();
-        token
+        self.tokens[self.current_idx + n].clone()
     }
 
     fn peek(&self) -> Token {
@@ -50,13 +55,16 @@ impl Parser {
 
     fn program(&mut self) -> Program {
         let mut items = vec![];
+        let mut spans = vec![];
 
         while self.current_idx < self.tokens.len() {
+            spans.push(self.span());
             items.push(self.item());
         }
 
         Program {
-            items
+            items,
+            spans,
         }
     }
 
@@ -99,6 +107,7 @@ impl Parser {
             Token::Identifier(i) => Some(i),
             _ => panic!("Expected identifier, got {:?}", ident_token),
         };
+        let annotation = self.annotation();
 
         let equal = self.next_token();
         if !matches!(equal, Token::Equal) {
@@ -107,6 +116,7 @@ impl Parser {
 
         Var {
             identifier: identifier.unwrap(),
+            annotation,
             expr: self.expression(),
         }
     }
@@ -122,6 +132,7 @@ impl Parser {
             Token::Identifier(i) => Some(i),
             _ => panic!("Expected identifier, got {:?}", ident_token),
         };
+        let annotation = self.annotation();
 
         let equal = self.next_token();
         if !matches!(equal, Token::Equal) {
@@ -130,6 +141,7 @@ impl Parser {
 
         Const {
             identifier: identifier.unwrap(),
+            annotation,
             expr: self.expression(),
         }
     }
@@ -146,22 +158,57 @@ impl Parser {
             _ => panic!("Expected identifier, got {:?}", ident_token),
         };
 
-        let args = self.proc_args();
+        let (args, arg_types) = self.proc_args();
+
+        let return_type = match self.peek() {
+            Token::Arrow => {
+                self.advance();
+                Some(self.ty())
+            }
+            _ => None,
+        };
 
         Proc {
             identifier: identifier.unwrap(),
             proc_args: args,
+            arg_types,
+            return_type,
             block: self.block(),
         }
     }
 
-    fn proc_args(&mut self) -> ProcArgs {
+    // The type after a colon, if there is one
+    fn annotation(&mut self) -> Option<Type> {
+        if !matches!(self.peek(), Token::Colon) {
+            return None;
+        }
+        self.advance();
+        Some(self.ty())
+    }
+
+    fn ty(&mut self) -> Type {
+        let token = self.next_token();
+        match token {
+            Token::Null => Type::Null,
+            Token::Identifier(ref name) => match name.as_str() {
+                "int" => Type::Int,
+                "float" => Type::Float,
+                "bool" => Type::Bool,
+                "string" => Type::String,
+                _ => panic!("Expected type, got {:?}", token),
+            },
+            _ => panic!("Expected type, got {:?}", token),
+        }
+    }
+
+    fn proc_args(&mut self) -> (ProcArgs, Vec<Option<Type>>) {
         let left_paren = self.next_token();
         if !matches!(left_paren, Token::LeftParen) {
             panic!("Expected '(', got {:?}", left_paren);
         }
 
         let mut args: ProcArgs = vec![];
+        let mut types = vec![];
 
         loop {
             let token = self.next_token();
@@ -170,6 +217,7 @@ impl Parser {
                 Token::RightParen => break,
                 _ => panic!("Expected identifier, got {:?}", token),
             }
+            types.push(self.annotation());
 
             let next_token = self.next_token();
             match next_token {
@@ -179,7 +227,7 @@ impl Parser {
             }
         }
 
-        args
+        (args, types)
     }
 
     fn assignment(&mut self) -> Assignment {
@@ -208,11 +256,13 @@ impl Parser {
         }
 
         let mut items = vec![];
+        let mut spans = vec![];
         while self.current_idx < self.tokens.len() {
             let token = self.peek();
             match token {
                 Token::RightBrace => break,
                 _ => {
+                    spans.push(self.span());
                     items.push(self.item());
                 }
             }
@@ -221,7 +271,8 @@ impl Parser {
         self.advance();
 
         Block {
-            items
+            items,
+            spans,
         }
     }
 
@@ -234,7 +285,7 @@ impl Parser {
         }
 
         self.retreat();
-        return Expression::Binary(self.binary());
+        Expression::Binary(self.binary())
     }
 
     fn binary(&mut self) -> Binary {
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use crate::vm::*;
+
+// Peephole optimizations over an unloaded program, the instructions and label
+// definitions that are passed to VM::load. Removed instructions are compacted
+// away and every jump, call and label address is moved to match.
+//
+//     push v / pop r        ->  move r v
+//     pushr x / pop y       ->  copy y x, or nothing when x == y
+//     copy r r, noop        ->  nothing
+//     move r v              ->  nothing, if r is written again before a read
+//     jeq 1 2 l             ->  nothing, comparisons of constants are static
+//     jeq 1 1 l             ->  jump l
+//     jump l / l: jump m    ->  jump m
+//     jump next             ->  nothing
+//
+// Pairs are only combined when nothing jumps between them.
+pub fn optimize(
+    mut instructions: Vec<Instruction>,
+    mut labels: Vec<(String, Address)>,
+) -> (Vec<Instruction>, Vec<(String, Address)>) {
+    loop {
+        let leaders = leaders(&instructions, &labels);
+        let mut removed = vec![false; instructions.len()];
+        let mut changed = false;
+
+        for i in 0..instructions.len() {
+            if removed[i] {
+                continue;
+            }
+            let next = i + 1;
+            let pair = next < instructions.len() && !leaders.contains(&(next as Address));
+
+            let replacement = match &instructions[i] {
+                Instruction::Noop => Some(None),
+                Instruction::Copy(to, from) if to == from => Some(None),
+                Instruction::JEQ(a, b, target) => Some(static_jump(a == b, *target)),
+                Instruction::JGT(a, b, target) => Some(static_jump(a > b, *target)),
+                Instruction::JGE(a, b, target) => Some(static_jump(a >= b, *target)),
+                Instruction::Jump(target) => {
+                    let target = thread(&instructions, *target);
+                    if target as usize == next {
+                        Some(None)
+                    } else if !matches!(instructions[i], Instruction::Jump(t) if t == target) {
+                        Some(Some(Instruction::Jump(target)))
+                    } else {
+                        None
+                    }
+                }
+                Instruction::Push(value) if pair => match instructions[next] {
+                    Instruction::Pop(register) => {
+                        removed[next] = true;
+                        Some(Some(Instruction::Move(register, *value)))
+                    }
+                    _ => None,
+                },
+                Instruction::PushR(from) if pair => match instructions[next] {
+                    Instruction::Pop(to) => {
+                        removed[next] = true;
+                        Some((to != *from).then_some(Instruction::Copy(to, *from)))
+                    }
+                    _ => None,
+                },
+                | Instruction::Move(register, _)
+                | Instruction::Copy(register, _)
+                | Instruction::LC(register, _)
+                | Instruction::LA(register, _) => {
+                    if is_dead(&instructions, &leaders, i, *register) {
+                        Some(None)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+
+            match replacement {
+                Some(Some(instruction)) => {
+                    instructions[i] = instruction;
+                    changed = true;
+                }
+                Some(None) => {
+                    removed[i] = true;
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+
+        if !changed {
+            return (instructions, labels);
+        }
+        (instructions, labels) = compact(instructions, labels, &removed);
+    }
+}
+
+fn static_jump(taken: bool, target: Address) -> Option<Instruction> {
+    taken.then_some(Instruction::Jump(target))
+}
+
+// Follows a chain of jumps to where it finally lands
+fn thread(instructions: &[Instruction], mut target: Address) -> Address {
+    let mut seen = HashSet::new();
+    while let Some(Instruction::Jump(next)) = instructions.get(target as usize) {
+        if !seen.insert(target) {
+            break;
+        }
+        target = *next;
+    }
+    target
+}
+
+// Addresses control can arrive at other than by falling through
+fn leaders(instructions: &[Instruction], labels: &[(String, Address)]) -> HashSet<Address> {
+    let mut leaders: HashSet<Address> = labels.iter().map(|(_, address)| *address).collect();
+    leaders.insert(0);
+
+    for (address, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            | Instruction::Jump(target)
+            | Instruction::JEQ(_, _, target)
+            | Instruction::JGT(_, _, target)
+            | Instruction::JGE(_, _, target)
+            | Instruction::LA(_, Target::Address(target)) => {
+                leaders.insert(*target);
+            }
+            // Calls return to the instruction after them
+            Instruction::Call(target) => {
+                if let Target::Address(target) = target {
+                    leaders.insert(*target);
+                }
+                leaders.insert(address as Address + 1);
+            }
+            _ => {}
+        }
+    }
+
+    leaders
+}
+
+// Whether the value written to the register at `address` is overwritten
+// before anything reads it, without leaving the basic block
+fn is_dead(instructions: &[Instruction], leaders: &HashSet<Address>, address: usize, register: Register) -> bool {
+    for (i, instruction) in instructions.iter().enumerate().skip(address + 1) {
+        if leaders.contains(&(i as Address)) {
+            return false;
+        }
+
+        // The callee can read any register before it writes A
+        if instruction.reads().contains(&register) || matches!(instruction, Instruction::Call(_)) {
+            return false;
+        }
+        if instruction.writes().contains(&register) {
+            return true;
+        }
+
+        let ends_block = matches!(
+            instruction,
+            | Instruction::Ret
+            | Instruction::Jump(_)
+            | Instruction::JEQ(..)
+            | Instruction::JGT(..)
+            | Instruction::JGE(..)
+        );
+        if ends_block {
+            return false;
+        }
+    }
+    false
+}
+
+fn compact(
+    instructions: Vec<Instruction>,
+    labels: Vec<(String, Address)>,
+    removed: &[bool],
+) -> (Vec<Instruction>, Vec<(String, Address)>) {
+    // New address of every old one, removed instructions map to the next
+    // instruction that is kept
+    let mut addresses = vec![0; instructions.len() + 1];
+    let mut kept = 0;
+    for (address, removed) in removed.iter().enumerate() {
+        addresses[address] = kept;
+        if !removed {
+            kept += 1;
+        }
+    }
+    addresses[instructions.len()] = kept;
+    let relocate = |address: Address| match addresses.get(address as usize) {
+        Some(address) => *address,
+        None => address - instructions.len() as Address + kept,
+    };
+
+    let mut compacted = vec![];
+    for (address, mut instruction) in instructions.iter().cloned().enumerate() {
+        if removed[address] {
+            continue;
+        }
+
+        match &mut instruction {
+            | Instruction::Jump(target)
+            | Instruction::JEQ(_, _, target)
+            | Instruction::JGT(_, _, target)
+            | Instruction::JGE(_, _, target)
+            | Instruction::Call(Target::Address(target))
+            | Instruction::LA(_, Target::Address(target)) => *target = relocate(*target),
+            _ => {}
+        }
+        compacted.push(instruction);
+    }
+
+    let labels = labels
+        .into_iter()
+        .map(|(label, address)| (label, relocate(address)))
+        .collect();
+
+    (compacted, labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // Runs the program, returning how it stopped and what it printed
+    fn run(mut vm: VM) -> (ExitStatus, String) {
+        vm.capture_output();
+        let status = vm.run().expect("program failed");
+        (status, String::from_utf8(vm.take_output().unwrap()).unwrap())
+    }
+
+    fn optimized(vm: &VM) -> VM {
+        let labels = vm.labels().iter().map(|(label, address)| (label.clone(), *address)).collect();
+        let (instructions, labels) = optimize(vm.instructions().to_vec(), labels);
+        VM::load(instructions, labels, vm.constants().clone()).unwrap()
+    }
+
+    // Runs the program with and without optimizing it
+    fn check(source: &str) -> VM {
+        let vm = assemble(source).unwrap();
+        assert_eq!(run(optimized(&vm)), run(assemble(source).unwrap()));
+        optimized(&vm)
+    }
+
+    #[test]
+    fn output_is_unchanged() {
+        let vm = check(
+            r#"
+            .string "hi\n"
+            main:
+                noop
+                move a 1
+                move a 2
+                push 5
+                pop b
+                pushr b
+                pop c
+                add a c
+                syscall writeint 1 a
+                jump one
+            one:
+                jump two
+                noop
+            two:
+                jeq 1 2 main
+                jeq 1 1 out
+                move d 9
+            out:
+                call f
+                syscall write 1 0 3
+                ret
+            f:
+                enter 0
+                copy a a
+                leave 0
+                ret
+            "#,
+        );
+        assert_eq!(vm.instructions().len(), 15);
+    }
+
+    #[test]
+    fn recursion_is_unchanged() {
+        check(
+            r#"
+            main:
+                push 10
+                call count
+                syscall writeint 1 a
+                ret
+            count:
+                enter 0
+                lf a -2
+                jeq 0 0 body
+            body:
+                lf b -2
+                not b
+                pushr b
+                pop c
+                copy d c
+                not d
+                not d
+                move c 0
+                sf a -2
+                lf a -2
+                leave 1
+                ret
+            "#,
+        );
+    }
+
+    #[test]
+    fn keeps_registers_a_callee_reads() {
+        let vm = check(
+            r#"
+            main:
+                move a 7
+                call show
+                ret
+            show:
+                syscall writeint 1 a
+                ret
+            "#,
+        );
+        assert_eq!(run(vm), (ExitStatus::Returned(7), "7".to_string()));
+    }
+
+    #[test]
+    fn calls_return_to_a_leader() {
+        let instructions = [Instruction::Call(Target::Address(2)), Instruction::Ret, Instruction::Ret];
+        let leaders = leaders(&instructions, &[]);
+        assert!(leaders.contains(&1) && leaders.contains(&2));
+    }
+}
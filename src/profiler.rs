@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use crate::assembler::label_names;
+use crate::vm::{Address, Instruction};
+
+const REPORT_LIMIT: usize = 10;
+
+#[derive(Default, Clone, Copy)]
+struct ProcStats {
+    calls: u64,
+    instructions: u64, // Executed in the proc itself, not its callees
+    time: Duration, // Including callees
+}
+
+struct Frame {
+    target: Address,
+    start: Instant,
+}
+
+// Counts executed instructions per address, per proc and per loop. A proc is
+// identified by the address it was called at, the program entry is address 0.
+//
+// A jump right after a Leave is a tail call, the frame is gone so it can only
+// go to another proc. The callee takes the caller's place on the stack and
+// is charged from there on, as if it had been called.
+pub struct Profiler {
+    names: HashMap<Address, String>,
+    counts: Vec<u64>,
+    procs: HashMap<Address, ProcStats>,
+    loops: HashMap<Address, u64>, // Taken backward jumps per target
+    stacks: HashMap<Vec<Address>, u64>, // Instructions per call stack
+    frames: Vec<Frame>,
+    stack: Vec<Address>,
+    left: bool, // The last instruction was a Leave
+}
+
+impl Profiler {
+    pub fn new(labels: &HashMap<String, Address>, instructions: usize) -> Self {
+        Profiler {
+            names: label_names(labels),
+            counts: vec![0; instructions],
+            procs: HashMap::new(),
+            loops: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: vec![],
+            stack: vec![0],
+            left: false,
+        }
+    }
+
+    // Called after each instruction with the ip and call depth it left behind
+    pub fn record(&mut self, ip: Address, instruction: &Instruction, next_ip: Address, depth: usize) {
+        if let Some(count) = self.counts.get_mut(ip as usize) {
+            *count += 1;
+        }
+
+        let current = *self.stack.last().unwrap();
+        self.procs.entry(current).or_default().instructions += 1;
+        match self.stacks.get_mut(self.stack.as_slice()) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+
+        let jump = matches!(
+            instruction,
+            Instruction::Jump(_) | Instruction::JEQ(..) | Instruction::JGT(..) | Instruction::JGE(..)
+        );
+        if jump && next_ip <= ip {
+            *self.loops.entry(next_ip).or_default() += 1;
+        }
+
+        let tail_call = self.left && matches!(instruction, Instruction::Jump(_)) && !self.frames.is_empty();
+        self.left = matches!(instruction, Instruction::Leave(_));
+        if tail_call {
+            self.return_to(self.frames.len() - 1);
+        }
+        if depth > self.frames.len() {
+            self.procs.entry(next_ip).or_default().calls += 1;
+            self.frames.push(Frame { target: next_ip, start: Instant::now() });
+            self.stack.push(next_ip);
+        }
+        self.return_to(depth);
+    }
+
+    // Time the calls that are still running when the program stops
+    pub fn finish(&mut self) {
+        self.return_to(0);
+    }
+
+    fn return_to(&mut self, depth: usize) {
+        while depth < self.frames.len() {
+            let frame = self.frames.pop().unwrap();
+            self.stack.pop();
+
+            // Recursive calls are already timed by the outermost frame
+            if !self.frames.iter().any(|f| f.target == frame.target) {
+                self.procs.entry(frame.target).or_default().time += frame.start.elapsed();
+            }
+        }
+    }
+
+    fn name(&self, address: Address) -> String {
+        match self.names.get(&address) {
+            Some(name) => name.clone(),
+            None if address == 0 => "<entry>".to_string(),
+            None => format!("<{address}>"),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        let mut procs: Vec<(&Address, &ProcStats)> = self.procs.iter().collect();
+        procs.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        report += "Hot procs\n";
+        report += &format!("{:>12} {:>8} {:>12}  proc\n", "instructions", "calls", "time");
+        for (address, stats) in procs.iter().take(REPORT_LIMIT) {
+            report += &format!(
+                "{:>12} {:>8} {:>12?}  {}\n",
+                stats.instructions,
+                stats.calls,
+                stats.time,
+                self.name(**address)
+            );
+        }
+
+        let mut loops: Vec<(&Address, &u64)> = self.loops.iter().collect();
+        loops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        report += "\nHot loops\n";
+        report += &format!("{:>12}  header\n", "iterations");
+        for (address, iterations) in loops.iter().take(REPORT_LIMIT) {
+            report += &format!("{iterations:>12}  {} ({address})\n", self.name(**address));
+        }
+
+        let mut instructions: Vec<(usize, &u64)> = self.counts.iter().enumerate().filter(|(_, c)| **c > 0).collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(&b.0)));
+        report += "\nHot instructions\n";
+        report += &format!("{:>12}  address\n", "executions");
+        for (address, count) in instructions.iter().take(REPORT_LIMIT) {
+            report += &format!("{count:>12}  {address}\n");
+        }
+
+        report
+    }
+
+    // One line per call stack, `main;fact;fact 42`, weighted by the number of
+    // instructions executed in it. This is the input format of flamegraph.pl
+    // and inferno.
+    pub fn write_folded(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack.iter().map(|address| self.name(*address)).collect();
+                format!("{} {count}", names.join(";"))
+            })
+            .collect();
+        lines.sort();
+
+        for line in lines {
+            writeln!(output, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::vm::Limits;
+
+    // f tail calls g, then the top level loops until the instruction budget
+    // runs out
+    const SOURCE: &str = "
+        main:
+            call f
+        loop:
+            move a 1
+            jump loop
+        f:
+            enter 0
+            leave 0
+            jump g
+        g:
+            ret
+    ";
+
+    fn profile() -> super::Profiler {
+        let mut vm = assemble(SOURCE).unwrap();
+        vm.set_limits(Limits { instructions: Some(20), ..Limits::default() });
+        vm.enable_profiling();
+        vm.run().unwrap_err();
+        vm.take_profile().unwrap()
+    }
+
+    // Instructions, calls and name of each proc in the report, times vary
+    fn procs(report: &str) -> Vec<String> {
+        let table = report.split("\n\n").next().unwrap();
+        table
+            .lines()
+            .skip(2)
+            .map(|line| {
+                let columns: Vec<&str> = line.split_whitespace().collect();
+                format!("{} {} {}", columns[0], columns[1], columns[3])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_hot_procs_loops_and_instructions() {
+        let report = profile().report();
+        assert_eq!(procs(&report), ["16 0 main", "3 1 f", "1 1 g"]);
+        assert!(report.contains("Hot loops\n  iterations  header\n           7  loop (1)\n"));
+        assert!(report.contains("Hot instructions\n  executions  address\n           8  1\n           7  2\n"));
+    }
+
+    // The jump to g comes right after f's Leave, so g is charged as if f had
+    // called it
+    #[test]
+    fn writes_folded_stacks() {
+        let mut folded = vec![];
+        profile().write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 16\nmain;f 3\nmain;g 1\n");
+    }
+}
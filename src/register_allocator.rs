@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use crate::ast::{BinaryOperator, Identifier, Type};
+use crate::ir::{self, BlockId, Function, Temp, Terminator, Value, Variable};
+use crate::value_types::Types;
+use crate::vm::*;
+
+// Registers handed out to temps. D is kept free as scratch, for loading
+// spilled temps and for results that go straight to a spill slot.
+pub const ALLOCATABLE: [Register; 3] = [Register::A, Register::B, Register::C];
+pub const SCRATCH: Register = Register::D;
+
+// Where a temp lives while it is live
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Home {
+    Register(Register),
+    Spilled(u32), // Spill slot, numbered from 0
+}
+
+pub struct Allocation {
+    pub homes: Vec<Home>, // Indexed by temp
+    pub spills: u32,
+}
+
+// Positions the temp is live over, from where it is assigned to its last use
+#[derive(Clone, Copy)]
+struct Interval {
+    temp: Temp,
+    start: usize,
+    end: usize,
+}
+
+// Linear scan allocation (Poletto and Sarkar). Instructions are numbered in
+// block order, which covers every path since jumps only go forward. Temps
+// are taken in order of where they start and get a free register, when
+// there is none the temp that ends last is spilled.
+//
+// Registers are caller saved, so temps that are live across a call are
+// spilled up front.
+pub fn allocate(function: &Function) -> Allocation {
+    let mut intervals: Vec<Option<Interval>> = vec![None; function.temps];
+    let mut calls = vec![];
+    let mut position = 0;
+
+    let mut extend = |temp: Temp, position: usize| {
+        let interval = intervals[temp].get_or_insert(Interval { temp, start: position, end: position });
+        interval.start = interval.start.min(position);
+        interval.end = interval.end.max(position);
+    };
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let ir::Instruction::Call(..) = instruction {
+                calls.push(position);
+            }
+            if let Some(temp) = instruction.defines() {
+                extend(temp, position);
+            }
+            for temp in instruction.uses() {
+                extend(temp, position);
+            }
+            position += 1;
+        }
+        for temp in block.terminator.uses() {
+            extend(temp, position);
+        }
+        position += 1;
+    }
+
+    let mut homes = vec![Home::Register(ALLOCATABLE[0]); function.temps];
+    let mut spills = 0;
+    let mut spill = |homes: &mut Vec<Home>, temp: Temp| {
+        homes[temp] = Home::Spilled(spills);
+        spills += 1;
+    };
+
+    let mut intervals: Vec<Interval> = intervals.into_iter().flatten().collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.temp));
+
+    let mut active: Vec<Interval> = vec![];
+    for interval in intervals {
+        if calls.iter().any(|call| interval.start < *call && *call < interval.end) {
+            spill(&mut homes, interval.temp);
+            continue;
+        }
+
+        // Temps that die here free their register for the one starting here
+        active.retain(|other| other.end > interval.start);
+
+        let used: Vec<Home> = active.iter().map(|other| homes[other.temp]).collect();
+        let free = ALLOCATABLE.iter().find(|register| !used.contains(&Home::Register(**register)));
+        match free {
+            Some(register) => {
+                homes[interval.temp] = Home::Register(*register);
+                active.push(interval);
+            }
+            None => {
+                let (index, last) = active
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, other)| (other.end, other.temp))
+                    .map(|(index, other)| (index, *other))
+                    .unwrap();
+
+                if last.end > interval.end {
+                    homes[interval.temp] = homes[last.temp];
+                    spill(&mut homes, last.temp);
+                    active.remove(index);
+                    active.push(interval);
+                } else {
+                    spill(&mut homes, interval.temp);
+                }
+            }
+        }
+    }
+
+    Allocation { homes, spills }
+}
+
+// Lowers an IR function to VM instructions, with every temp in the home the
+// allocator gave it. Spill slots come after the locals in the frame.
+//
+// Ints are themselves, true is 1, false and null are 0. Floats and strings
+// are the address of their constant, strings end with a NUL byte.
+pub struct FunctionLowering<'a> {
+    pub instructions: &'a mut Vec<Instruction>,
+    pub constants: &'a mut ConstantPool,
+    pub jumps: &'a mut Vec<(usize, BlockId)>, // Jumps to patch once blocks have addresses
+    pub tail_calls: &'a mut Vec<(usize, Identifier)>, // Jumps to patch once procs have addresses
+    pub scope: &'a HashMap<Variable, Location>,
+    pub allocation: &'a Allocation,
+    pub types: &'a Types,
+    pub locals: u32,
+    pub args: u32, // Popped by a return
+}
+
+// Where a variable lives, a slot in the current frame or a global word
+#[derive(Debug, Clone, Copy)]
+pub enum Location {
+    Frame(Offset),
+    Global(Address),
+}
+
+impl FunctionLowering<'_> {
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn slot(&self, slot: u32) -> Offset {
+        (self.locals + slot) as Offset
+    }
+
+    // The register the temp is computed in, D if it lives in a spill slot
+    fn target(&self, temp: Temp) -> Register {
+        match self.allocation.homes[temp] {
+            Home::Register(register) => register,
+            Home::Spilled(_) => SCRATCH,
+        }
+    }
+
+    // Puts the temp in its spill slot after it was computed in its target
+    fn spill(&mut self, temp: Temp) {
+        if let Home::Spilled(slot) = self.allocation.homes[temp] {
+            self.emit(Instruction::SF(SCRATCH, self.slot(slot)));
+        }
+    }
+
+    // The register holding the temp, spilled temps are loaded into `scratch`
+    fn read(&mut self, temp: Temp, scratch: Register) -> Register {
+        match self.allocation.homes[temp] {
+            Home::Register(register) => register,
+            Home::Spilled(slot) => {
+                self.emit(Instruction::LF(scratch, self.slot(slot)));
+                scratch
+            }
+        }
+    }
+
+    // Copies the temp into `register`
+    fn read_into(&mut self, temp: Temp, register: Register) {
+        let from = self.read(temp, register);
+        if from != register {
+            self.emit(Instruction::Copy(register, from));
+        }
+    }
+
+    pub fn function(&mut self, function: &Function, blocks: &mut Vec<Address>) {
+        let definitions = function.definitions();
+        for block in &function.blocks {
+            blocks.push(self.instructions.len() as Address);
+
+            // The top level's frame holds the globals, so only procs can
+            // give theirs up to a call
+            let (instructions, tail_call) = match (block.instructions.split_last(), &block.terminator) {
+                (Some((ir::Instruction::Call(temp, identifier, args), rest)), Terminator::Return(value))
+                    if temp == value && args.len() as u32 <= self.args && !function.name.is_empty() =>
+                {
+                    (rest, Some((identifier, args)))
+                }
+                _ => (block.instructions.as_slice(), None),
+            };
+            for instruction in instructions {
+                self.instruction(function, instruction, &definitions);
+            }
+
+            match (tail_call, &block.terminator) {
+                (Some((identifier, args)), _) => self.tail_call(identifier, args),
+                // Only ints are exit statuses, like in the other backends
+                (None, Terminator::Return(_))
+                    if function.name.is_empty() && self.types.returns(function) != Type::Int =>
+                {
+                    self.emit(Instruction::Move(Register::A, 0));
+                    self.emit(Instruction::Leave(self.args));
+                    self.emit(Instruction::Ret);
+                }
+                (None, Terminator::Return(temp)) => {
+                    self.read_into(*temp, Register::A);
+                    self.emit(Instruction::Leave(self.args));
+                    self.emit(Instruction::Ret);
+                }
+                (None, Terminator::Jump(target)) => {
+                    self.jumps.push((self.instructions.len(), *target));
+                    self.emit(Instruction::Jump(0));
+                }
+            }
+        }
+    }
+
+    // A call whose value is returned reuses the caller's frame. The args are
+    // written over the caller's, Leave pops the rest of the frame and the
+    // jump leaves the return address alone, so the callee returns straight
+    // to the caller's caller and recursion in tail position runs in constant
+    // space. The callee can't take more args than the caller, they would be
+    // written over the saved frame pointer.
+    fn tail_call(&mut self, identifier: &Identifier, args: &[Temp]) {
+        let unused = self.args - args.len() as u32;
+        for (index, arg) in args.iter().enumerate() {
+            let register = self.read(*arg, SCRATCH);
+            self.emit(Instruction::SF(register, VM::arg_offset(index as u32) - unused as Offset));
+        }
+        self.emit(Instruction::Leave(unused));
+        self.tail_calls.push((self.instructions.len(), identifier.clone()));
+        self.emit(Instruction::Jump(0));
+    }
+
+    fn instruction(
+        &mut self,
+        function: &Function,
+        instruction: &ir::Instruction,
+        definitions: &HashMap<Temp, &ir::Instruction>,
+    ) {
+        match instruction {
+            ir::Instruction::Const(temp, value) => {
+                let register = self.target(*temp);
+                let instruction = match value {
+                    Value::Null | Value::Bool(false) => Instruction::Move(register, 0),
+                    Value::Bool(true) => Instruction::Move(register, 1),
+                    // Checked to fit by VM::check_words
+                    Value::Int(int) => Instruction::Move(register, *int as i32 as u32),
+                    Value::Float(float) => Instruction::LC(register, self.constants.add(Constant::Float(*float))),
+                    Value::String(string) => {
+                        Instruction::LC(register, self.constants.add(Constant::String(format!("{string}\0"))))
+                    }
+                };
+                self.emit(instruction);
+                self.spill(*temp);
+            }
+            ir::Instruction::Load(temp, variable) => {
+                let register = self.target(*temp);
+                match self.scope[variable] {
+                    Location::Frame(offset) => self.emit(Instruction::LF(register, offset)),
+                    Location::Global(address) => self.emit(Instruction::LW(register, address)),
+                }
+                self.spill(*temp);
+            }
+            ir::Instruction::Store(variable, temp) => {
+                let register = self.read(*temp, SCRATCH);
+                match self.scope[variable] {
+                    Location::Frame(offset) => self.emit(Instruction::SF(register, offset)),
+                    Location::Global(address) => self.emit(Instruction::SW(register, address)),
+                }
+            }
+            ir::Instruction::Binary(temp, operator, left, right) => self.binary(*temp, operator, *left, *right),
+            ir::Instruction::Not(temp, value) => self.not(*temp, *value, self.types.temp(function, *value)),
+            // The allocator spills every temp that is live across a call, so
+            // no registers need to be saved
+            ir::Instruction::Call(temp, identifier, args) => {
+                for arg in args.iter().rev() {
+                    let register = self.read(*arg, SCRATCH);
+                    self.emit(Instruction::PushR(register));
+                }
+                self.emit(Instruction::Call(Target::Label(identifier.clone())));
+                match self.allocation.homes[*temp] {
+                    Home::Register(Register::A) => {}
+                    Home::Register(register) => self.emit(Instruction::Copy(register, Register::A)),
+                    Home::Spilled(slot) => self.emit(Instruction::SF(Register::A, self.slot(slot))),
+                }
+            }
+            ir::Instruction::Println(temp, args) => {
+                self.println(function, args, definitions);
+                let register = self.target(*temp);
+                self.emit(Instruction::Move(register, 0));
+                self.spill(*temp);
+            }
+        }
+    }
+
+    // The left operand is computed into the target and the right one has to
+    // be in another register. When the target is the scratch register and
+    // the right operand is spilled a register is borrowed with PushR/Pop.
+    fn binary(&mut self, temp: Temp, operator: &BinaryOperator, left: Temp, right: Temp) {
+        let register = self.target(temp);
+        let mut borrowed = None;
+        let operand = match self.allocation.homes[right] {
+            Home::Register(operand) if operand != register => operand,
+            Home::Register(operand) => {
+                self.emit(Instruction::Copy(SCRATCH, operand));
+                SCRATCH
+            }
+            Home::Spilled(slot) if register != SCRATCH => {
+                self.emit(Instruction::LF(SCRATCH, self.slot(slot)));
+                SCRATCH
+            }
+            Home::Spilled(_) => {
+                self.read_into(left, register);
+                let operand = ALLOCATABLE[0];
+                self.emit(Instruction::PushR(operand));
+                self.read_into(right, operand);
+                borrowed = Some(operand);
+                operand
+            }
+        };
+        if borrowed.is_none() {
+            self.read_into(left, register);
+        }
+
+        let instruction = match operator {
+            BinaryOperator::Plus => Instruction::Add(register, operand),
+            BinaryOperator::Minus => Instruction::Sub(register, operand),
+            BinaryOperator::Multiply => Instruction::Mul(register, operand),
+            BinaryOperator::Divide => Instruction::Div(register, operand),
+        };
+        self.emit(instruction);
+        if let Some(operand) = borrowed {
+            self.emit(Instruction::Pop(operand));
+        }
+        self.spill(temp);
+    }
+
+    // Strings are false when empty, their first byte is the NUL. Floats are
+    // false when they are 0 or -0, doubling the high word drops the sign bit,
+    // and the word after the address is read through a second register,
+    // borrowed with PushR/Pop when the target is the scratch register.
+    fn not(&mut self, temp: Temp, value: Temp, value_type: Type) {
+        let register = self.target(temp);
+        match value_type {
+            Type::Null => self.emit(Instruction::Move(register, 1)),
+            Type::Bool | Type::Int => {
+                self.read_into(value, register);
+                self.emit(Instruction::Not(register));
+            }
+            Type::String => {
+                self.read_into(value, register);
+                self.emit(Instruction::LR(register, register));
+                self.emit(Instruction::Not(register));
+            }
+            Type::Float => {
+                self.read_into(value, register);
+                let high = if register == SCRATCH { ALLOCATABLE[0] } else { SCRATCH };
+                if register == SCRATCH {
+                    self.emit(Instruction::PushR(high));
+                }
+                self.emit(Instruction::Move(high, 1));
+                self.emit(Instruction::Add(high, register));
+                self.emit(Instruction::LR(high, high));
+                self.emit(Instruction::Add(high, high));
+                self.emit(Instruction::Not(high));
+                self.emit(Instruction::LR(register, register));
+                self.emit(Instruction::Not(register));
+                self.emit(Instruction::Mul(register, high));
+                if register == SCRATCH {
+                    self.emit(Instruction::Pop(high));
+                }
+            }
+        }
+        self.spill(temp);
+    }
+
+    // Literals are written from the constant pool, other values by their type
+    fn println(&mut self, function: &Function, args: &[Temp], definitions: &HashMap<Temp, &ir::Instruction>) {
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.write(" ");
+            }
+
+            match definitions.get(arg) {
+                Some(ir::Instruction::Const(_, Value::String(string))) => self.write(string),
+                Some(ir::Instruction::Const(_, Value::Float(float))) => {
+                    let index = self.constants.add(Constant::Float(*float));
+                    self.emit(Instruction::SysCall(SysCall::WriteFloat {
+                        file_descriptor: STDOUT,
+                        buf_pointer: self.constants.address(index),
+                    }));
+                }
+                _ => {
+                    let value_type = self.types.temp(function, *arg);
+                    if value_type == Type::Null {
+                        self.write("null");
+                        continue;
+                    }
+                    let register = self.read(*arg, SCRATCH);
+                    let file_descriptor = STDOUT;
+                    self.emit(Instruction::SysCall(match value_type {
+                        Type::Bool => SysCall::WriteBool { file_descriptor, register },
+                        Type::String => SysCall::WriteString { file_descriptor, register },
+                        Type::Float => SysCall::WriteFloatR { file_descriptor, register },
+                        _ => SysCall::WriteInt { file_descriptor, register },
+                    }));
+                }
+            }
+        }
+        self.write("\n");
+    }
+
+    fn write(&mut self, string: &str) {
+        let index = self.constants.add(Constant::String(string.to_string()));
+        self.emit(Instruction::SysCall(SysCall::Write {
+            file_descriptor: STDOUT,
+            buf_pointer: self.constants.address(index),
+            count: string.len() as u32,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Module;
+    use crate::verifier::verify;
+
+    fn compile(source: &str) -> Module {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        module
+    }
+
+    fn run(module: &Module) -> String {
+        let mut vm = VM::from_ir(module).unwrap();
+        verify(&vm).unwrap();
+        vm.capture_output();
+        vm.run().unwrap();
+        String::from_utf8(vm.take_output().unwrap()).unwrap()
+    }
+
+    // Operators are right associative and have no precedence, so the right
+    // operand nests and every left operand stays live until it returns
+    #[test]
+    fn spills_deep_expressions() {
+        let module = compile(
+            "
+            proc f(a, b, c, d, e, g) {
+                return a * (b + (c * (d - (e + (g * (a - b))))))
+            }
+            println(f(2, 3, 4, 5, 6, 7))
+            ",
+        );
+        assert!(allocate(&module.procs[0]).spills > 0);
+        assert_eq!(run(&module), "54\n");
+    }
+
+    #[test]
+    fn spills_temps_live_across_calls() {
+        let module = compile(
+            "
+            proc id(n) {
+                return n
+            }
+            proc f(a, b) {
+                return a - (b * (a + id(b)))
+            }
+            println(f(3, 4))
+            ",
+        );
+        assert_eq!(run(&module), "-25\n");
+    }
+
+    #[test]
+    fn evaluates_left_to_right() {
+        let module = compile(
+            "
+            var x = 1
+            var log = 0
+            proc bump() {
+                x = 10
+                return 5
+            }
+            proc g(n) {
+                log = n + log * 10
+                return n
+            }
+            println(x + bump())
+            println(g(1), g(2), g(3))
+            println(log)
+            ",
+        );
+        assert_eq!(run(&module), "6\n1 2 3\n123\n");
+    }
+
+    #[test]
+    fn prints_values_by_type() {
+        let module = compile(
+            "
+            proc show(s, f, b, n, i) {
+                println(s, f, b, n, i)
+            }
+            show(\"hello world\", 2.5, true, null, 7)
+            show(\"\", 0.1, false, null, 0)
+            ",
+        );
+        assert_eq!(run(&module), "hello world 2.5 true null 7\n 0.1 false null 0\n");
+    }
+
+    // The nots live across the call to id are spilled, so they are computed
+    // in the scratch register
+    #[test]
+    fn negates_values_by_type() {
+        let module = compile(
+            "
+            proc id(n) {
+                return n
+            }
+            proc falsy(f, s) {
+                println(! f, ! s, ! null)
+                println(! f, ! s, id(0))
+            }
+            falsy(0.0, \"\")
+            falsy(2.5, \"a\")
+            ",
+        );
+        assert!(allocate(&module.procs[1]).spills > 0);
+        assert_eq!(run(&module), "true true true\ntrue true 0\nfalse false true\nfalse false 0\n");
+    }
+
+    #[test]
+    fn rejects_arithmetic_on_floats_and_strings() {
+        let module = compile(
+            "
+            println(\"s\" + \"t\")
+            println(1.5 + 2)
+            ",
+        );
+        let diagnostics = VM::from_ir(&module).err().unwrap();
+        let messages: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "error: the VM backend cannot apply + to string and string at the top level",
+                "error: the VM backend cannot apply + to float and int at the top level",
+            ]
+        );
+    }
+
+    #[test]
+    fn exits_with_ints_only() {
+        let status = |source: &str| {
+            let mut vm = VM::from_ir(&compile(source)).unwrap();
+            vm.capture_output();
+            vm.run().unwrap()
+        };
+        assert_eq!(status("proc main() {\n return 3\n}"), ExitStatus::Returned(3));
+        assert_eq!(status("proc main() {\n return true\n}"), ExitStatus::Returned(0));
+        assert_eq!(status("proc main() {\n return 2.5\n}"), ExitStatus::Returned(0));
+    }
+}
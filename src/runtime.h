@@ -0,0 +1,187 @@
+/* Runtime for C generated by code_generator.rs. Every value is dynamically
+ * typed, operations check the types of their operands at run time and abort
+ * with a message when they don't fit. */
+
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef enum { RT_NULL, RT_BOOL, RT_INT, RT_FLOAT, RT_STRING } Kind;
+
+typedef struct {
+    Kind kind;
+    union {
+        int boolean;
+        int64_t integer;
+        double floating;
+        const char *string;
+    } as;
+} Value;
+
+static inline Value rt_null(void) {
+    Value value;
+    value.kind = RT_NULL;
+    value.as.integer = 0;
+    return value;
+}
+
+static inline Value rt_bool(int boolean) {
+    Value value;
+    value.kind = RT_BOOL;
+    value.as.boolean = boolean != 0;
+    return value;
+}
+
+static inline Value rt_int(int64_t integer) {
+    Value value;
+    value.kind = RT_INT;
+    value.as.integer = integer;
+    return value;
+}
+
+static inline Value rt_float(double floating) {
+    Value value;
+    value.kind = RT_FLOAT;
+    value.as.floating = floating;
+    return value;
+}
+
+static inline Value rt_string(const char *string) {
+    Value value;
+    value.kind = RT_STRING;
+    value.as.string = string;
+    return value;
+}
+
+static inline void rt_error(const char *message) {
+    fprintf(stderr, "Runtime error: %s\n", message);
+    exit(1);
+}
+
+static inline int rt_truthy(Value value) {
+    switch (value.kind) {
+    case RT_NULL: return 0;
+    case RT_BOOL: return value.as.boolean;
+    case RT_INT: return value.as.integer != 0;
+    case RT_FLOAT: return value.as.floating != 0.0;
+    case RT_STRING: return value.as.string[0] != '\0';
+    }
+    return 0;
+}
+
+static inline int rt_is_number(Value value) {
+    return value.kind == RT_INT || value.kind == RT_FLOAT;
+}
+
+static inline double rt_to_float(Value value) {
+    return value.kind == RT_INT ? (double) value.as.integer : value.as.floating;
+}
+
+/* Integers wrap around like i64 in Rust, the arithmetic is done unsigned
+ * because signed overflow is undefined in C */
+static inline Value rt_arithmetic(char operator, Value left, Value right) {
+    if (!rt_is_number(left) || !rt_is_number(right)) {
+        rt_error("Invalid operands to arithmetic");
+    }
+
+    if (left.kind == RT_INT && right.kind == RT_INT) {
+        uint64_t a = (uint64_t) left.as.integer;
+        uint64_t b = (uint64_t) right.as.integer;
+        switch (operator) {
+        case '+': return rt_int((int64_t) (a + b));
+        case '-': return rt_int((int64_t) (a - b));
+        case '*': return rt_int((int64_t) (a * b));
+        default:
+            if (right.as.integer == 0) {
+                rt_error("Division by zero");
+            }
+            if (left.as.integer == INT64_MIN && right.as.integer == -1) {
+                return left;
+            }
+            return rt_int(left.as.integer / right.as.integer);
+        }
+    }
+
+    double a = rt_to_float(left);
+    double b = rt_to_float(right);
+    switch (operator) {
+    case '+': return rt_float(a + b);
+    case '-': return rt_float(a - b);
+    case '*': return rt_float(a * b);
+    default: return rt_float(a / b);
+    }
+}
+
+/* Strings are never freed, generated programs are short lived */
+static inline Value rt_add(Value left, Value right) {
+    if (left.kind == RT_STRING && right.kind == RT_STRING) {
+        size_t length = strlen(left.as.string);
+        char *string = malloc(length + strlen(right.as.string) + 1);
+        if (string == NULL) {
+            rt_error("Out of memory");
+        }
+        strcpy(string, left.as.string);
+        strcpy(string + length, right.as.string);
+        return rt_string(string);
+    }
+    return rt_arithmetic('+', left, right);
+}
+
+static inline Value rt_sub(Value left, Value right) {
+    return rt_arithmetic('-', left, right);
+}
+
+static inline Value rt_mul(Value left, Value right) {
+    return rt_arithmetic('*', left, right);
+}
+
+static inline Value rt_div(Value left, Value right) {
+    return rt_arithmetic('/', left, right);
+}
+
+static inline Value rt_not(Value value) {
+    return rt_bool(!rt_truthy(value));
+}
+
+/* Shortest representation that reads back as the same double */
+static inline void rt_print_float(double floating) {
+    char buffer[32];
+    int precision;
+    for (precision = 1; precision < 17; precision++) {
+        snprintf(buffer, sizeof buffer, "%.*g", precision, floating);
+        if (strtod(buffer, NULL) == floating) {
+            break;
+        }
+    }
+    snprintf(buffer, sizeof buffer, "%.*g", precision, floating);
+    fputs(buffer, stdout);
+}
+
+static inline void rt_print(Value value) {
+    switch (value.kind) {
+    case RT_NULL: fputs("null", stdout); break;
+    case RT_BOOL: fputs(value.as.boolean ? "true" : "false", stdout); break;
+    case RT_INT: printf("%lld", (long long) value.as.integer); break;
+    case RT_FLOAT: rt_print_float(value.as.floating); break;
+    case RT_STRING: fputs(value.as.string, stdout); break;
+    }
+}
+
+static inline Value rt_println(int count, const Value *values) {
+    int i;
+    for (i = 0; i < count; i++) {
+        if (i > 0) {
+            fputs(" ", stdout);
+        }
+        rt_print(values[i]);
+    }
+    fputs("\n", stdout);
+    return rt_null();
+}
+
+/* Exit status for a return from the top level */
+static inline int rt_exit_code(Value value) {
+    return value.kind == RT_INT ? (int) value.as.integer : 0;
+}
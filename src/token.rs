@@ -1,13 +1,31 @@
+use std::fmt;
+
+// Where a token starts in the source, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
     Comma,
+    Colon,
     Const,
     Dot,
     Minus,
+    Arrow,
     Plus,
     Semicolon,
     Slash,
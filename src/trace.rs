@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use crate::assembler::{format_instruction, label_names};
+use crate::vm::{Address, Instruction};
+
+// One executed instruction, with registers A-D before and after it ran
+pub struct TraceEntry<'a> {
+    pub ip: Address,
+    pub instruction: &'a str,
+    pub before: [u32; 4],
+    pub after: [u32; 4],
+}
+
+impl fmt::Display for TraceEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.before;
+        write!(f, "{:>6}: {:<24} a={a} b={b} c={c} d={d}", self.ip, self.instruction)?;
+
+        // Only print the registers that changed
+        for (name, (before, after)) in ["a", "b", "c", "d"].iter().zip(self.before.iter().zip(self.after)) {
+            if *before != after {
+                write!(f, " {name}<-{after}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait TraceSink {
+    fn record(&mut self, entry: &TraceEntry) -> io::Result<()>;
+}
+
+// Any writer can be used as a sink, one line per instruction
+impl<W: Write> TraceSink for W {
+    fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        writeln!(self, "{entry}")
+    }
+}
+
+pub struct Tracer {
+    sink: Box<dyn TraceSink>,
+    names: HashMap<Address, String>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn TraceSink>, labels: &HashMap<String, Address>) -> Self {
+        Tracer {
+            sink,
+            names: label_names(labels),
+        }
+    }
+
+    pub fn record(&mut self, ip: Address, instruction: &Instruction, before: [u32; 4], after: [u32; 4]) -> io::Result<()> {
+        let entry = TraceEntry {
+            ip,
+            instruction: &format_instruction(instruction, &self.names),
+            before,
+            after,
+        };
+        self.sink.record(&entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::assemble;
+    use crate::vm::ExitStatus;
+
+    // A Vec<u8> the test can still read once the VM owns the sink
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_every_instruction() {
+        let mut vm = assemble(
+            "
+            main:
+                move a 2
+                push 3
+                call add
+                ret
+            add:
+                pop b
+                add a b
+                ret
+            ",
+        )
+        .unwrap();
+        let log = Rc::new(RefCell::new(vec![]));
+        vm.set_trace(Box::new(Shared(log.clone())));
+        assert_eq!(vm.run().unwrap(), ExitStatus::Returned(5));
+
+        let log = String::from_utf8(log.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "     0: move a 2                 a=0 b=0 c=0 d=0 a<-2",
+                "     1: push 3                   a=2 b=0 c=0 d=0",
+                "     2: call add                 a=2 b=0 c=0 d=0",
+                "     4: pop b                    a=2 b=0 c=0 d=0 b<-3",
+                "     5: add a b                  a=2 b=3 c=0 d=0 a<-5",
+                "     6: ret                      a=5 b=3 c=0 d=0",
+                "     3: ret                      a=5 b=3 c=0 d=0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{collect_procs, duplicate_procs, PRINTLN};
+use crate::token::Span;
+
+// The annotated type of each name in scope, None if it has no annotation
+type Scope = HashMap<Identifier, Option<Type>>;
+
+// Checks a program against its type annotations before anything runs. Names
+// without an annotation can hold any value, so only what is certain to go
+// wrong is reported: operands no runtime accepts, like "a" - 1, calls with
+// the wrong number of args, and values that don't match an annotation.
+// Names that aren't declared where they are used and procs declared twice
+// are reported too, with the same scopes the IR resolves names in.
+//
+// Diagnostics point at the item they were found in.
+pub fn check(root: &Program) -> Vec<Diagnostic> {
+    let mut procs = vec![];
+    collect_procs(root, &mut procs);
+
+    let mut checker = Checker {
+        procs: procs.iter().map(|(proc, _)| (proc.identifier.clone(), proc)).collect(),
+        globals: HashMap::new(),
+        diagnostics: duplicate_procs(&procs),
+        span: Span::default(),
+        proc: None,
+        returned: false,
+    };
+    checker.globals = checker.declare(root, HashMap::new());
+    let globals = checker.globals.clone();
+    checker.block(root, &globals);
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    procs: HashMap<Identifier, &'a Proc>,
+    globals: Scope, // Seen by every proc
+    diagnostics: Vec<Diagnostic>,
+    span: Span, // Of the item being checked
+    proc: Option<&'a Proc>, // Being checked, None at the top level
+    returned: bool, // Whether the proc has a return
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic::error(message).at(self.span));
+    }
+
+    // Adds the vars and consts declared in the block. A name declared more
+    // than once has the first annotation and the others have to agree.
+    fn declare(&mut self, block: &Block, mut scope: Scope) -> Scope {
+        for (item, span) in block.items.iter().zip(&block.spans) {
+            let (identifier, annotation) = match item {
+                | Item::Declaration(Declaration::Var(Var { identifier, annotation, .. }))
+                | Item::Declaration(Declaration::Const(Const { identifier, annotation, .. })) => (identifier, annotation),
+                _ => continue,
+            };
+            match (scope.get(identifier).copied().flatten(), annotation) {
+                (Some(declared), Some(annotation)) if declared != *annotation => {
+                    self.span = *span;
+                    self.error(format!("{identifier} is already declared as {declared}"));
+                }
+                (Some(_), _) => {}
+                (None, annotation) => {
+                    scope.insert(identifier.clone(), *annotation);
+                }
+            }
+        }
+        scope
+    }
+
+    fn block(&mut self, block: &'a Block, scope: &Scope) {
+        for (item, span) in block.items.iter().zip(&block.spans) {
+            self.span = *span;
+            match item {
+                | Item::Assignment(Assignment { identifier, expr })
+                | Item::Declaration(Declaration::Var(Var { identifier, expr, .. }))
+                | Item::Declaration(Declaration::Const(Const { identifier, expr, .. })) => {
+                    if !scope.contains_key(identifier) {
+                        self.error(format!("undefined variable {identifier}"));
+                    }
+                    let value = self.expression(expr, scope);
+                    if let (Some(Some(declared)), Some(value)) = (scope.get(identifier), value) {
+                        if *declared != value {
+                            self.error(format!("{identifier} is {declared}, got {value}"));
+                        }
+                    }
+                }
+                Item::Declaration(Declaration::Proc(proc)) => self.proc(proc),
+                Item::Expression(expr) => {
+                    self.expression(expr, scope);
+                }
+            }
+        }
+    }
+
+    // Procs only see the globals, their args and their own vars and consts,
+    // not those of a proc they are nested in
+    fn proc(&mut self, proc: &'a Proc) {
+        let span = self.span;
+        let args = proc.proc_args.iter().cloned().zip(proc.arg_types.iter().copied()).collect();
+        let locals = self.declare(&proc.block, args);
+        let mut scope = self.globals.clone();
+        scope.extend(locals);
+
+        let outer = (self.proc.replace(proc), std::mem::take(&mut self.returned));
+        self.block(&proc.block, &scope);
+        self.span = span;
+        // Falling off the end returns null
+        match proc.return_type {
+            Some(declared) if declared != Type::Null && !self.returned => {
+                self.error(format!("proc {} returns {declared} but can end without returning", proc.identifier));
+            }
+            _ => {}
+        }
+        (self.proc, self.returned) = outer;
+    }
+
+    fn expression(&mut self, expression: &Expression, scope: &Scope) -> Option<Type> {
+        match expression {
+            Expression::Unary(unary) => self.unary(unary, scope),
+            Expression::Binary(Binary { left, operator, right }) => {
+                let left = self.unary(left, scope);
+                let right = self.expression(right, scope);
+                self.binary(operator, left, right)
+            }
+        }
+    }
+
+    // Arithmetic takes numbers, and + also takes two strings
+    fn binary(&mut self, operator: &BinaryOperator, left: Option<Type>, right: Option<Type>) -> Option<Type> {
+        let plus = matches!(operator, BinaryOperator::Plus);
+        let result = match (left, right) {
+            (Some(Type::Int), Some(Type::Int)) => Ok(Some(Type::Int)),
+            (Some(Type::Int | Type::Float), Some(Type::Int | Type::Float)) => Ok(Some(Type::Float)),
+            (Some(Type::String), Some(Type::String) | None) | (None, Some(Type::String)) if plus => {
+                Ok(Some(Type::String))
+            }
+            (Some(Type::Float), None) | (None, Some(Type::Float)) => Ok(Some(Type::Float)),
+            (Some(Type::Int), None) | (None, Some(Type::Int)) | (None, None) => Ok(None),
+            _ => Err(()),
+        };
+
+        result.unwrap_or_else(|_| {
+            let symbol = match operator {
+                BinaryOperator::Plus => "+",
+                BinaryOperator::Minus => "-",
+                BinaryOperator::Multiply => "*",
+                BinaryOperator::Divide => "/",
+            };
+            let operands: Vec<String> = left.into_iter().chain(right).map(|operand| operand.to_string()).collect();
+            self.error(format!("cannot apply {symbol} to {}", operands.join(" and ")));
+            None
+        })
+    }
+
+    fn unary(&mut self, unary: &Unary, scope: &Scope) -> Option<Type> {
+        match unary {
+            Unary::UnaryOperation { operator: UnaryOperator::Negate, unary } => {
+                self.unary(unary, scope);
+                Some(Type::Bool)
+            }
+            Unary::Call(Call::CallLiteral { identifier, call_args }) => {
+                let args: Vec<Option<Type>> = call_args.iter().map(|arg| self.expression(arg, scope)).collect();
+                if identifier == PRINTLN {
+                    return Some(Type::Null);
+                }
+                let Some(proc) = self.procs.get(identifier).copied() else {
+                    self.error(format!("call to undefined proc {identifier}"));
+                    return None;
+                };
+
+                if args.len() != proc.proc_args.len() {
+                    let message = format!("proc {identifier} takes {} args, got {}", proc.proc_args.len(), args.len());
+                    self.error(message);
+                }
+                for ((name, declared), arg) in proc.proc_args.iter().zip(&proc.arg_types).zip(args) {
+                    if let (Some(declared), Some(arg)) = (declared, arg) {
+                        if *declared != arg {
+                            self.error(format!("arg {name} of proc {identifier} is {declared}, got {arg}"));
+                        }
+                    }
+                }
+                proc.return_type
+            }
+            Unary::Call(Call::Primary(primary)) => self.primary(primary, scope),
+        }
+    }
+
+    fn primary(&mut self, primary: &Primary, scope: &Scope) -> Option<Type> {
+        match primary {
+            Primary::True | Primary::False => Some(Type::Bool),
+            Primary::Null => Some(Type::Null),
+            Primary::Int(_) => Some(Type::Int),
+            Primary::Float(_) => Some(Type::Float),
+            Primary::String(_) => Some(Type::String),
+            Primary::Identifier(identifier) => match scope.get(identifier) {
+                Some(annotation) => *annotation,
+                None => {
+                    self.error(format!("undefined variable {identifier}"));
+                    None
+                }
+            },
+            // The top level returns an exit status, which has no annotation
+            Primary::Return(expression) => {
+                let value = self.expression(expression, scope);
+                self.returned = true;
+                if let (Some(proc), Some(value)) = (self.proc, value) {
+                    match proc.return_type {
+                        Some(declared) if declared != value => {
+                            self.error(format!("proc {} returns {declared}, got {value}", proc.identifier));
+                        }
+                        _ => {}
+                    }
+                }
+                // Nothing after it runs
+                None
+            }
+            Primary::Expression(expression) => self.expression(expression, scope),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::String => write!(f, "string"),
+            Type::Null => write!(f, "null"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<String> {
+        check(&crate::parse(source.to_string())).iter().map(|diagnostic| diagnostic.to_string()).collect()
+    }
+
+    #[test]
+    fn reports_undefined_variables() {
+        let messages = messages(
+            "
+            var total = 0
+            proc outer(n) {
+                var local = n
+                proc inner() {
+                    return local + total
+                }
+                return inner()
+            }
+            missing = total
+            println(outer(1), unknown)
+            ",
+        );
+        assert_eq!(
+            messages,
+            ["error: undefined variable local", "error: undefined variable missing", "error: undefined variable unknown"]
+        );
+    }
+
+    #[test]
+    fn reports_procs_declared_twice() {
+        let messages = messages(
+            "
+            proc twice() {
+                return 1
+            }
+            proc other() {
+                proc twice() {
+                    return 2
+                }
+                return twice()
+            }
+            ",
+        );
+        assert_eq!(messages, ["error: proc twice is already declared"]);
+    }
+}
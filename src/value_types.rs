@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use crate::ast::{BinaryOperator, Identifier, Type};
+use crate::diagnostic::Diagnostic;
+use crate::ir::*;
+
+// The type a backend gives `left op right`, None if it can't compute it
+pub type Arithmetic = fn(&BinaryOperator, Type, Type) -> Option<Type>;
+
+// The type of every temp, variable and proc result in a program, for
+// backends whose values are bare machine words. Those can't tell at run time
+// whether a word is an int, a bool or the address of a string, so each value
+// has to have one type that is known when compiling.
+//
+// Types are found by following values from constants through variables,
+// args and returns until nothing changes. A variable, arg or proc result
+// that gets values of two types is an error, and so is arithmetic the
+// backend can't do. Values that are never computed, like variables read
+// before they are written, are null, which is what zeroed memory holds.
+pub struct Types {
+    temps: HashMap<Identifier, Vec<Option<Type>>>, // Of each function
+    variables: HashMap<(Identifier, Variable), Type>, // Globals have no function
+    returns: HashMap<Identifier, Type>,
+}
+
+impl Types {
+    pub fn temp(&self, function: &Function, temp: Temp) -> Type {
+        self.temps[&function.name][temp].unwrap_or(Type::Null)
+    }
+
+    pub fn variable(&self, function: &Function, variable: &Variable) -> Type {
+        self.variables.get(&key(function, variable)).copied().unwrap_or(Type::Null)
+    }
+
+    pub fn returns(&self, function: &Function) -> Type {
+        self.returns.get(&function.name).copied().unwrap_or(Type::Null)
+    }
+}
+
+pub fn infer(module: &Module, backend: &str, arithmetic: Arithmetic) -> Result<Types, Vec<Diagnostic>> {
+    let functions: Vec<&Function> = std::iter::once(&module.entry).chain(&module.procs).collect();
+    let mut inference = Inference {
+        types: Types {
+            temps: functions.iter().map(|function| (function.name.clone(), vec![None; function.temps])).collect(),
+            variables: HashMap::new(),
+            returns: HashMap::new(),
+        },
+        params: module.procs.iter().map(|proc| (proc.name.clone(), &proc.params)).collect(),
+        backend,
+        arithmetic,
+        diagnostics: vec![],
+        changed: false,
+    };
+
+    // Errors are only reported on the last pass, once every type is known
+    loop {
+        inference.changed = false;
+        for function in &functions {
+            inference.function(function, false);
+        }
+        if !inference.changed {
+            break;
+        }
+    }
+    for function in &functions {
+        inference.function(function, true);
+    }
+
+    if !inference.diagnostics.is_empty() {
+        return Err(inference.diagnostics);
+    }
+    Ok(inference.types)
+}
+
+fn key(function: &Function, variable: &Variable) -> (Identifier, Variable) {
+    match variable {
+        Variable::Local(_) => (function.name.clone(), variable.clone()),
+        Variable::Global(_) => (String::new(), variable.clone()),
+    }
+}
+
+fn type_of(value: &Value) -> Type {
+    match value {
+        Value::Null => Type::Null,
+        Value::Bool(_) => Type::Bool,
+        Value::Int(_) => Type::Int,
+        Value::Float(_) => Type::Float,
+        Value::String(_) => Type::String,
+    }
+}
+
+struct Inference<'a> {
+    types: Types,
+    params: HashMap<Identifier, &'a Vec<Identifier>>,
+    backend: &'a str,
+    arithmetic: Arithmetic,
+    diagnostics: Vec<Diagnostic>,
+    changed: bool,
+}
+
+impl Inference<'_> {
+    fn error(&mut self, function: &Function, temp: Temp, message: String) {
+        let origin = &function.origins[temp];
+        let message = format!("{message} {}, the {} backend needs one type for it", origin.context(), self.backend);
+        self.diagnostics.push(Diagnostic::error(message).at(origin.span));
+    }
+
+    fn temp(&self, function: &Function, temp: Temp) -> Option<Type> {
+        self.types.temps[&function.name][temp]
+    }
+
+    fn set_temp(&mut self, function: &Function, temp: Temp, value: Type) {
+        let slot = &mut self.types.temps.get_mut(&function.name).unwrap()[temp];
+        if slot.is_none() {
+            *slot = Some(value);
+            self.changed = true;
+        }
+    }
+
+    // Where two types meet the first one found stays, the other is reported
+    fn join(&mut self, known: Option<Type>, value: Type, report: bool) -> Result<Option<Type>, Type> {
+        match known {
+            None => {
+                self.changed = true;
+                Ok(Some(value))
+            }
+            Some(known) if known == value => Ok(None),
+            Some(known) => {
+                if report {
+                    return Err(known);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // `temp` is the value stored, diagnostics point at it
+    fn set_variable(&mut self, function: &Function, variable: &Variable, temp: Temp, report: bool) {
+        let Some(value) = self.temp(function, temp) else {
+            return;
+        };
+        let key = key(function, variable);
+        match self.join(self.types.variables.get(&key).copied(), value, report) {
+            Ok(Some(value)) => {
+                self.types.variables.insert(key, value);
+            }
+            Ok(None) => {}
+            Err(known) => self.error(function, temp, format!("{} holds {known} and {value}", variable.name())),
+        }
+    }
+
+    fn function(&mut self, function: &Function, report: bool) {
+        for block in &function.blocks {
+            for instruction in &block.instructions {
+                self.instruction(function, instruction, report);
+            }
+
+            if let Terminator::Return(temp) = block.terminator {
+                let Some(value) = self.temp(function, temp) else {
+                    continue;
+                };
+                match self.join(self.types.returns.get(&function.name).copied(), value, report) {
+                    Ok(Some(value)) => {
+                        self.types.returns.insert(function.name.clone(), value);
+                    }
+                    Ok(None) => {}
+                    Err(known) => {
+                        let message = match function.name.as_str() {
+                            "" => format!("the top level returns {known} and {value}"),
+                            name => format!("proc {name} returns {known} and {value}"),
+                        };
+                        self.error(function, temp, message);
+                    }
+                }
+            }
+        }
+    }
+
+    fn instruction(&mut self, function: &Function, instruction: &Instruction, report: bool) {
+        match instruction {
+            Instruction::Const(temp, value) => self.set_temp(function, *temp, type_of(value)),
+            Instruction::Load(temp, variable) => {
+                if let Some(value) = self.types.variables.get(&key(function, variable)).copied() {
+                    self.set_temp(function, *temp, value);
+                }
+            }
+            Instruction::Store(variable, temp) => self.set_variable(function, variable, *temp, report),
+            Instruction::Binary(temp, operator, left, right) => {
+                let (Some(left), Some(right)) = (self.temp(function, *left), self.temp(function, *right)) else {
+                    return;
+                };
+                match (self.arithmetic)(operator, left, right) {
+                    Some(value) => self.set_temp(function, *temp, value),
+                    None if report => {
+                        let symbol = match operator {
+                            BinaryOperator::Plus => "+",
+                            BinaryOperator::Minus => "-",
+                            BinaryOperator::Multiply => "*",
+                            BinaryOperator::Divide => "/",
+                        };
+                        let origin = &function.origins[*temp];
+                        let message = format!(
+                            "the {} backend cannot apply {symbol} to {left} and {right} {}",
+                            self.backend,
+                            origin.context()
+                        );
+                        self.diagnostics.push(Diagnostic::error(message).at(origin.span));
+                    }
+                    None => {}
+                }
+            }
+            Instruction::Not(temp, _) => self.set_temp(function, *temp, Type::Bool),
+            Instruction::Call(temp, name, args) => {
+                let params = self.params[name];
+                for (param, arg) in params.iter().zip(args) {
+                    let Some(value) = self.temp(function, *arg) else {
+                        continue;
+                    };
+                    let key = (name.clone(), Variable::Local(param.clone()));
+                    match self.join(self.types.variables.get(&key).copied(), value, report) {
+                        Ok(Some(value)) => {
+                            self.types.variables.insert(key, value);
+                        }
+                        Ok(None) => {}
+                        Err(known) => {
+                            self.error(function, *arg, format!("arg {param} of proc {name} is passed {known} and {value}"));
+                        }
+                    }
+                }
+                if let Some(value) = self.types.returns.get(name).copied() {
+                    self.set_temp(function, *temp, value);
+                }
+            }
+            Instruction::Println(temp, _) => self.set_temp(function, *temp, Type::Null),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer_source(source: &str) -> Result<(Module, Types), Vec<Diagnostic>> {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        let types = infer(&module, "test", |_, left, right| match (left, right) {
+            (Type::Int, Type::Int) => Some(Type::Int),
+            _ => None,
+        })?;
+        Ok((module, types))
+    }
+
+    #[test]
+    fn follows_values_through_variables_args_and_returns() {
+        let (module, types) = infer_source(
+            "
+            var name = \"world\"
+            proc greeting(who) {
+                return who
+            }
+            proc twice(n) {
+                return n + n
+            }
+            proc main() {
+                return greeting(name)
+            }
+            twice(2)
+            ",
+        )
+        .unwrap();
+        let proc = |name: &str| module.procs.iter().find(|proc| proc.name == name).unwrap();
+        assert_eq!(types.returns(proc("greeting")), Type::String);
+        assert_eq!(types.returns(proc("twice")), Type::Int);
+        assert_eq!(types.returns(proc("main")), Type::String);
+    }
+
+    #[test]
+    fn rejects_values_of_two_types() {
+        let diagnostics = infer_source(
+            "
+            var x = 1
+            proc id(a) {
+                return a
+            }
+            x = true
+            id(1)
+            id(\"a\")
+            println(1 + 2.5)
+            ",
+        )
+        .err()
+        .unwrap();
+        let messages: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "error: x holds int and bool at the top level, the test backend needs one type for it",
+                "error: arg a of proc id is passed int and string at the top level, the test backend needs one type for it",
+                "error: the test backend cannot apply + to int and float at the top level",
+            ]
+        );
+    }
+}
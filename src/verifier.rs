@@ -0,0 +1,346 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use crate::vm::*;
+
+// Static checks run over a loaded program before it is executed. Every entry
+// point (address 0 and every call target) is walked along all control flow
+// paths, tracking the stack depth relative to the entry and which registers
+// have definitely been written.
+//
+// A call to a proc that never returns, so its stack effect is never found,
+// loses the depth. The code after it is still checked, except for the stack,
+// and its returns don't count towards the effect of the proc it is in.
+
+#[derive(Debug)]
+pub enum VerifyErrorKind {
+    JumpOutOfRange(Address),
+    UnknownLabel(String),
+    BadConstant(ConstantIndex),
+    StackUnderflow,
+    UnbalancedStack { expected: i64, found: i64 },
+    UnbalancedReturn { expected: i64, found: i64 },
+    LeaveWithoutEnter,
+    NestedEnter,
+    ReturnInsideFrame,
+    UninitializedRegister(Register),
+}
+
+#[derive(Debug)]
+pub struct VerifyError {
+    pub address: Address,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.address)?;
+        match &self.kind {
+            VerifyErrorKind::JumpOutOfRange(target) => write!(f, "jump to {target} is out of range"),
+            VerifyErrorKind::UnknownLabel(label) => write!(f, "unknown label {label}"),
+            VerifyErrorKind::BadConstant(index) => write!(f, "bad constant {index}"),
+            VerifyErrorKind::StackUnderflow => write!(f, "pops more than was pushed"),
+            VerifyErrorKind::UnbalancedStack { expected, found } => {
+                write!(f, "stack depth {found} does not match {expected} on another path")
+            }
+            VerifyErrorKind::UnbalancedReturn { expected, found } => {
+                write!(f, "returns with stack depth {found}, another return has {expected}")
+            }
+            VerifyErrorKind::LeaveWithoutEnter => write!(f, "leave without enter"),
+            VerifyErrorKind::NestedEnter => write!(f, "enter inside an open frame"),
+            VerifyErrorKind::ReturnInsideFrame => write!(f, "return without leave"),
+            VerifyErrorKind::UninitializedRegister(register) => {
+                write!(f, "register {register:?} is read before it is written")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct State {
+    depth: i64,
+    frame: Option<i64>, // Depth at the last Enter
+    written: u8, // Bit set of registers
+    lost: bool, // The depth is unknown
+}
+
+fn bit(register: Register) -> u8 {
+    match register {
+        Register::A => 1,
+        Register::B => 2,
+        Register::C => 4,
+        Register::D => 8,
+    }
+}
+
+struct Verifier<'a> {
+    vm: &'a VM,
+    errors: Vec<VerifyError>,
+    // Net stack effect of each proc, known once one of its returns is reached
+    effects: HashMap<Address, i64>,
+}
+
+pub fn verify(vm: &VM) -> Result<(), Vec<VerifyError>> {
+    let mut verifier = Verifier { vm, errors: vec![], effects: HashMap::new() };
+    verifier.check_operands();
+
+    if verifier.errors.is_empty() {
+        let mut entries = vec![0];
+        for instruction in vm.instructions() {
+            if let Instruction::Call(Target::Address(address)) = instruction {
+                if !entries.contains(address) {
+                    entries.push(*address);
+                }
+            }
+        }
+
+        // Effects of callees are discovered as we go, so repeat until they
+        // stop changing. Recursive procs need their base case found first.
+        loop {
+            let known = verifier.effects.len();
+            for &entry in &entries {
+                verifier.walk(entry, false);
+            }
+            if verifier.effects.len() == known {
+                break;
+            }
+        }
+
+        for &entry in &entries {
+            verifier.walk(entry, true);
+        }
+    }
+
+    if verifier.errors.is_empty() {
+        return Ok(());
+    }
+    Err(verifier.errors)
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, address: Address, kind: VerifyErrorKind) {
+        self.errors.push(VerifyError { address, kind });
+    }
+
+    fn check_operands(&mut self) {
+        let length = self.vm.instructions().len() as Address;
+
+        for (address, instruction) in self.vm.instructions().iter().enumerate() {
+            let address = address as Address;
+            match instruction {
+                | Instruction::Jump(target)
+                | Instruction::JEQ(_, _, target)
+                | Instruction::JGT(_, _, target)
+                | Instruction::JGE(_, _, target) if *target > length => {
+                    self.error(address, VerifyErrorKind::JumpOutOfRange(*target));
+                }
+                Instruction::Call(target) => match target {
+                    Target::Address(target) if *target >= length => {
+                        self.error(address, VerifyErrorKind::JumpOutOfRange(*target));
+                    }
+                    Target::Label(label) if !self.vm.labels().contains_key(label) => {
+                        self.error(address, VerifyErrorKind::UnknownLabel(label.clone()));
+                    }
+                    _ => {}
+                },
+                Instruction::LA(_, Target::Label(label)) if !self.vm.labels().contains_key(label) => {
+                    self.error(address, VerifyErrorKind::UnknownLabel(label.clone()));
+                }
+                Instruction::LC(_, index) if *index as usize >= self.vm.constants().len() => {
+                    self.error(address, VerifyErrorKind::BadConstant(*index));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn call_target(&self, target: &Target) -> Option<Address> {
+        match target {
+            Target::Address(address) => Some(*address),
+            Target::Label(label) => self.vm.labels().get(label).copied(),
+        }
+    }
+
+    // Walks one entry point. Errors are only reported on the final walk, when
+    // the effects of every callee are known.
+    fn walk(&mut self, entry: Address, report: bool) {
+        let vm = self.vm;
+        let instructions = vm.instructions();
+        let length = instructions.len() as Address;
+
+        let mut states: HashMap<Address, State> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut returns: Option<i64> = None;
+        let mut errors = vec![];
+
+        states.insert(entry, State { depth: 0, frame: None, written: 0, lost: false });
+        queue.push_back(entry);
+
+        while let Some(address) = queue.pop_front() {
+            if address >= length {
+                continue;
+            }
+
+            let mut state = states[&address];
+            let instruction = &instructions[address as usize];
+            let mut error = |kind| errors.push(VerifyError { address, kind });
+
+            for register in instruction.reads() {
+                if state.written & bit(register) == 0 {
+                    error(VerifyErrorKind::UninitializedRegister(register));
+                    state.written |= bit(register);
+                }
+            }
+            for register in instruction.writes() {
+                state.written |= bit(register);
+            }
+
+            let mut next = vec![address + 1];
+            match instruction {
+                Instruction::Push(_) | Instruction::PushR(_) => state.depth += 1,
+                Instruction::Pop(_) => {
+                    state.depth -= 1;
+                    let below = state.depth < 0 || state.frame.is_some_and(|frame| state.depth <= frame);
+                    if below && !state.lost {
+                        error(VerifyErrorKind::StackUnderflow);
+                        continue;
+                    }
+                }
+                Instruction::Enter(locals) => {
+                    if state.frame.is_some() {
+                        error(VerifyErrorKind::NestedEnter);
+                        continue;
+                    }
+                    state.frame = Some(state.depth);
+                    state.depth += 1 + *locals as i64;
+                }
+                Instruction::Leave(args) => {
+                    match state.frame {
+                        Some(frame) => state.depth = frame - *args as i64,
+                        None => {
+                            error(VerifyErrorKind::LeaveWithoutEnter);
+                            continue;
+                        }
+                    }
+                    state.frame = None;
+                }
+                Instruction::Call(target) => {
+                    let effect = self.call_target(target).and_then(|callee| self.effects.get(&callee));
+                    match effect {
+                        Some(effect) => state.depth += effect,
+                        // Not known yet, or never returns. Found effects are
+                        // picked up on a later pass.
+                        None => state.lost = true,
+                    }
+                }
+                Instruction::Ret => {
+                    if state.frame.is_some() {
+                        error(VerifyErrorKind::ReturnInsideFrame);
+                    }
+                    if state.lost {
+                        continue;
+                    }
+                    match returns {
+                        Some(expected) if expected != state.depth => {
+                            error(VerifyErrorKind::UnbalancedReturn { expected, found: state.depth });
+                        }
+                        _ => returns = Some(state.depth),
+                    }
+                    continue;
+                }
+                Instruction::Jump(target) => next = vec![*target],
+                | Instruction::JEQ(_, _, target)
+                | Instruction::JGT(_, _, target)
+                | Instruction::JGE(_, _, target) => next.push(*target),
+                _ => {}
+            }
+
+            for target in next {
+                match states.get_mut(&target) {
+                    None => {
+                        states.insert(target, state);
+                        queue.push_back(target);
+                    }
+                    Some(existing) => {
+                        let lost = existing.lost || state.lost;
+                        if existing.frame.is_some() != state.frame.is_some()
+                            || !lost && (existing.depth != state.depth || existing.frame != state.frame)
+                        {
+                            error(VerifyErrorKind::UnbalancedStack {
+                                expected: existing.depth,
+                                found: state.depth,
+                            });
+                            continue;
+                        }
+                        // Registers are only written if they are on every path
+                        let written = existing.written & state.written;
+                        if written != existing.written || lost != existing.lost {
+                            existing.written = written;
+                            existing.lost = lost;
+                            queue.push_back(target);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(effect) = returns {
+            self.effects.insert(entry, effect);
+        }
+        if report {
+            // Revisiting an instruction can report the same problem again
+            for error in errors {
+                let duplicate = self.errors.iter().any(|e| {
+                    e.address == error.address && e.to_string() == error.to_string()
+                });
+                if !duplicate {
+                    self.errors.push(error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn errors(source: &str) -> Vec<String> {
+        match verify(&assemble(source).unwrap()) {
+            Ok(()) => vec![],
+            Err(errors) => errors.iter().map(VerifyError::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn checks_code_after_calls_that_never_return() {
+        let source = "
+            main:
+                call spin
+                syscall writeint 1 b
+                ret
+            spin:
+                jump spin
+        ";
+        assert_eq!(errors(source), ["1: register B is read before it is written"]);
+    }
+
+    #[test]
+    fn accepts_tail_calls_that_never_return() {
+        let source = "
+            main:
+                enter 0
+                push 3
+                call count
+                leave 0
+                ret
+            count:
+                enter 0
+                lf a -2
+                sf a -2
+                leave 0
+                jump count
+        ";
+        assert_eq!(errors(source), Vec::<String>::new());
+    }
+}
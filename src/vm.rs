@@ -1,12 +1,43 @@
 use std::collections::HashMap;
-use crate::ast::*;
+use std::fmt;
+use crate::ast::Type;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{self, Module, Value, Variable};
+use crate::peephole;
+use crate::profiler::Profiler;
+use crate::register_allocator::{self, FunctionLowering, Location};
+use crate::trace::{TraceSink, Tracer};
+use crate::value_types;
 
-type Address = u32;
+use std::io::{Read, Write};
 
-const STDIN: u32 = 0;
-const STDOUT: u32 = 1;
+pub type Address = u32;
+pub type ConstantIndex = u32;
+pub type Offset = i32;
 
-#[derive(Copy, Clone)]
+pub const STDIN: u32 = 0;
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+const WORD_SIZE: usize = 4;
+const DEFAULT_CALL_DEPTH: usize = 1024;
+const DEFAULT_MEMORY: usize = 1 << 24; // Words, 64 MiB
+
+// Calling convention
+//
+// The caller pushes the arguments in reverse order and then calls the proc.
+// The callee opens its frame with Enter(locals), which saves the caller's
+// frame pointer and reserves its local slots, and returns with Leave(args)
+// followed by Ret. Leave also pops the arguments, so the caller has nothing
+// to clean up.
+//
+//     | arg n-1 | ... | arg 0 | saved fp | local 0 | ... | local m-1 |
+//                                         ^ fp
+//
+// Locals are at fp + i and arguments at fp - 2 - i, see VM::arg_offset.
+// The return value is passed in register A. Registers are caller saved.
+
+#[derive(Debug, Copy, Clone)]
 pub enum SysCall {
     Read {
         file_descriptor: u32,
@@ -18,9 +49,32 @@ pub enum SysCall {
         buf_pointer: u32,
         count: u32
     },
+    WriteFloat {
+        file_descriptor: u32,
+        buf_pointer: u32,
+    },
+    WriteInt {
+        file_descriptor: u32,
+        register: Register,
+    },
+    // Prints true or false
+    WriteBool {
+        file_descriptor: u32,
+        register: Register,
+    },
+    // The register holds the address of a string that ends with a NUL byte
+    WriteString {
+        file_descriptor: u32,
+        register: Register,
+    },
+    // Like WriteFloat with the address in a register
+    WriteFloatR {
+        file_descriptor: u32,
+        register: Register,
+    },
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Register {
     A,
     B,
@@ -28,35 +82,309 @@ pub enum Register {
     D
 }
 
-#[derive(Clone)]
+// Call and load targets are written as labels and resolved to addresses when
+// the program is loaded, see VM::load
+#[derive(Debug, Clone)]
+pub enum Target {
+    Label(String),
+    Address(Address),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Label(label) => write!(f, "{label}"),
+            Target::Address(address) => write!(f, "{address}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Instruction {
     Noop,
     Ret,
     Move(Register, u32), // Move(to, value)
+    Copy(Register, Register), // Copy(to, from)
+
+    // Arithmetic instructions, to = to op from
+    Add(Register, Register),
+    Sub(Register, Register),
+    Mul(Register, Register),
+    Div(Register, Register),
+    Not(Register), // register = register == 0
 
     // Jump instructions
     Jump(Address),
-    Call(String),
+    Call(Target),
     JEQ(u32, u32, Address), // If a == b, jump to address
     JGT(u32, u32, Address), // If a > b, jump to address
     JGE(u32, u32, Address), // If a >= b, jump to address
 
     // Memory instructions
-    LA(Register, String), // register = &string
+    LA(Register, Target), // register = &label
     LW(Register, Address), // register = *address
     SW(Register, Address), // *address = register
+    LC(Register, ConstantIndex), // register = &constants[index]
+    LR(Register, Register), // to = *from
     Push(u32), // stack.push(value)
+    PushR(Register), // stack.push(register)
     Pop(Register), // register = stack.pop()
 
-    // SysCall(SysCall)
+    // Frame instructions
+    Enter(u32), // Save fp and reserve n locals
+    Leave(u32), // Restore fp and pop n arguments
+    LF(Register, Offset), // register = *(fp + offset)
+    SF(Register, Offset), // *(fp + offset) = register
+
+    SysCall(SysCall)
+}
+
+impl Instruction {
+    // Registers whose value the instruction uses
+    pub fn reads(&self) -> Vec<Register> {
+        match self {
+            | Instruction::Add(to, from)
+            | Instruction::Sub(to, from)
+            | Instruction::Mul(to, from)
+            | Instruction::Div(to, from) => vec![*to, *from],
+            | Instruction::Copy(_, from)
+            | Instruction::LR(_, from) => vec![*from],
+            | Instruction::Not(register)
+            | Instruction::SW(register, _)
+            | Instruction::PushR(register)
+            | Instruction::SF(register, _)
+            | Instruction::SysCall(SysCall::WriteInt { register, .. })
+            | Instruction::SysCall(SysCall::WriteBool { register, .. })
+            | Instruction::SysCall(SysCall::WriteString { register, .. })
+            | Instruction::SysCall(SysCall::WriteFloatR { register, .. }) => vec![*register],
+            _ => vec![],
+        }
+    }
+
+    // Registers the instruction changes
+    pub fn writes(&self) -> Vec<Register> {
+        match self {
+            | Instruction::Move(register, _)
+            | Instruction::Copy(register, _)
+            | Instruction::Add(register, _)
+            | Instruction::Sub(register, _)
+            | Instruction::Mul(register, _)
+            | Instruction::Div(register, _)
+            | Instruction::Not(register)
+            | Instruction::LA(register, _)
+            | Instruction::LW(register, _)
+            | Instruction::LC(register, _)
+            | Instruction::LR(register, _)
+            | Instruction::Pop(register)
+            | Instruction::LF(register, _) => vec![*register],
+            // Return value
+            | Instruction::Call(_)
+            | Instruction::SysCall(SysCall::Read { .. }) => vec![Register::A],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Constant {
+    String(String),
+    Float(f64),
+}
+
+impl Constant {
+    // Number of words the constant takes up in the data segment
+    fn size(&self) -> u32 {
+        match self {
+            Constant::String(string) => string.len().div_ceil(WORD_SIZE) as u32,
+            Constant::Float(_) => 2,
+        }
+    }
+}
+
+// Literals that don't fit in a register. They are laid out one after the
+// other at the bottom of memory when the program is loaded, so the address of
+// every constant is known at compile time.
+#[derive(Clone, Default)]
+pub struct ConstantPool {
+    constants: Vec<Constant>,
+}
+
+impl ConstantPool {
+    pub fn new() -> Self {
+        ConstantPool { constants: vec![] }
+    }
+
+    pub fn add(&mut self, constant: Constant) -> ConstantIndex {
+        if let Constant::String(string) = &constant {
+            let existing = self.constants.iter().position(|c| {
+                matches!(c, Constant::String(s) if s == string)
+            });
+
+            if let Some(index) = existing {
+                return index as ConstantIndex;
+            }
+        }
+
+        self.push(constant)
+    }
+
+    // Like add, but always creates a new entry
+    pub fn push(&mut self, constant: Constant) -> ConstantIndex {
+        self.constants.push(constant);
+        (self.constants.len() - 1) as ConstantIndex
+    }
+
+    pub fn len(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Constant> {
+        self.constants.iter()
+    }
+
+    pub fn address(&self, index: ConstantIndex) -> Address {
+        self.constants[..index as usize].iter().map(Constant::size).sum()
+    }
+
+    pub fn data_segment(&self) -> Vec<u32> {
+        let mut data = vec![];
+
+        for constant in &self.constants {
+            match constant {
+                Constant::String(string) => {
+                    for chunk in string.as_bytes().chunks(WORD_SIZE) {
+                        let mut word = [0; WORD_SIZE];
+                        word[..chunk.len()].copy_from_slice(chunk);
+                        data.push(u32::from_le_bytes(word));
+                    }
+                }
+                Constant::Float(float) => {
+                    let bits = float.to_bits();
+                    data.push(bits as u32);
+                    data.push((bits >> 32) as u32);
+                }
+            }
+        }
+
+        data
+    }
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedLabel(label) => write!(f, "Undefined label {label}"),
+            LinkError::DuplicateLabel(label) => write!(f, "Duplicate label {label}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    Halted, // Ran past the last instruction
+    Returned(u32), // Returned from the outermost frame with the value in A
+    Paused, // Ran out of its time slice, see VM::run_for
+}
+
+// Bounds on what a program may use, so untrusted programs can't hang or
+// exhaust the host. Exceeding one stops the program with an error.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub instructions: Option<u64>, // Total instructions executed
+    pub memory: usize, // Words, including the data segment
+    pub call_depth: usize, // Nested calls on the ret stack
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            instructions: None,
+            memory: DEFAULT_MEMORY,
+            call_depth: DEFAULT_CALL_DEPTH,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmErrorKind {
+    StackUnderflow,
+    StackOverflow,
+    InstructionBudgetExceeded,
+    MemoryLimitExceeded,
+    BadAddress(Address),
+    BadJumpTarget(Address), // Past the end of the program
+    BadConstant(ConstantIndex),
+    BadFileDescriptor(u32),
+    UnknownLabel(String),
+    DivisionByZero,
+    Io(std::io::ErrorKind),
+}
+
+impl fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VmErrorKind::StackOverflow => write!(f, "stack overflow"),
+            VmErrorKind::InstructionBudgetExceeded => write!(f, "instruction budget exceeded"),
+            VmErrorKind::MemoryLimitExceeded => write!(f, "memory limit exceeded"),
+            VmErrorKind::BadAddress(address) => write!(f, "bad address {address}"),
+            VmErrorKind::BadJumpTarget(address) => write!(f, "bad jump target {address}"),
+            VmErrorKind::BadConstant(index) => write!(f, "bad constant {index}"),
+            VmErrorKind::BadFileDescriptor(fd) => write!(f, "bad file descriptor {fd}"),
+            VmErrorKind::UnknownLabel(label) => write!(f, "unknown label {label}"),
+            VmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            VmErrorKind::Io(kind) => write!(f, "io error: {kind}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VmError {
+    pub ip: Address,
+    pub instruction: Instruction,
+    pub kind: VmErrorKind,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}: {:?}", self.kind, self.ip, self.instruction)
+    }
+}
+
+// Everything that changes while a program runs
+#[derive(Debug, Clone)]
+pub struct VmState {
+    pub memory: Vec<u32>,
+    pub ret: Vec<u32>,
+    pub ip: Address,
+    pub fp: Address,
+    pub registers: [u32; 4],
+    pub executed: u64,
+    pub status: Option<ExitStatus>,
 }
 
 pub struct VM {
     instructions: Vec<Instruction>,
     labels: HashMap<String, Address>,
+    constants: ConstantPool,
     memory: Vec<Address>,
     ret: Vec<u32>,
+    limits: Limits,
+    executed: u64,
+    status: Option<ExitStatus>, // Set once the program has stopped
+    source_lines: Vec<usize>, // Source line of each instruction, if known
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    output: Option<Vec<u8>>, // Captured instead of written, see capture_output
     ip: u32,
+    fp: u32,
     a: u32,
     b: u32,
     c: u32,
@@ -68,9 +396,18 @@ impl VM {
         VM {
             instructions: vec![],
             labels: HashMap::new(),
+            constants: ConstantPool::new(),
             memory: vec![],
             ret: vec![],
+            limits: Limits::default(),
+            executed: 0,
+            status: None,
+            source_lines: vec![],
+            tracer: None,
+            profiler: None,
+            output: None,
             ip: 0,
+            fp: 0,
             a: 0,
             b: 0,
             c: 0,
@@ -78,88 +415,287 @@ impl VM {
         }
     }
 
-    pub fn from_ast(root: Program) -> Self {
-
+    pub fn load(
+        instructions: Vec<Instruction>,
+        labels: Vec<(String, Address)>,
+        constants: ConstantPool,
+    ) -> Result<Self, Vec<LinkError>> {
         let mut vm = VM::new();
+        vm.memory = constants.data_segment();
+        vm.instructions = instructions;
+        vm.constants = constants;
+        vm.link(labels)?;
+        Ok(vm)
+    }
+
+    // Rewrite every label target to the address it refers to
+    fn link(&mut self, labels: Vec<(String, Address)>) -> Result<(), Vec<LinkError>> {
+        let mut errors = vec![];
+
+        for (label, address) in labels {
+            if self.labels.contains_key(&label) {
+                errors.push(LinkError::DuplicateLabel(label));
+                continue;
+            }
+            self.labels.insert(label, address);
+        }
+
+        for instruction in self.instructions.iter_mut() {
+            let target = match instruction {
+                Instruction::Call(target) => target,
+                Instruction::LA(_, target) => target,
+                _ => continue,
+            };
 
-        for item in root.items {
-            match item {
-                Item::Assignment(ass) => {
-                    let Assignment { identifier, expr } = ass;
-                    todo!()
+            if let Target::Label(label) = target {
+                match self.labels.get(label) {
+                    Some(&address) => *target = Target::Address(address),
+                    None => errors.push(LinkError::UndefinedLabel(label.clone())),
                 }
-                Item::Declaration(decl) => {
-                    match decl {
-                        Declaration::Var(var) => {
-                            let Var {identifier, expr} = var;
-                            todo!()
-                        }
-                        Declaration::Const(const_) => {
-                            let Const {identifier, expr} = const_;
-                            todo!()
-                        }
-                        Declaration::Proc(proc) => {
-                            let Proc {identifier, proc_args, block} = proc;
-                            todo!()
-                        }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(())
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Number of instructions executed so far
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn state(&self) -> VmState {
+        VmState {
+            memory: self.memory.clone(),
+            ret: self.ret.clone(),
+            ip: self.ip,
+            fp: self.fp,
+            registers: self.registers(),
+            executed: self.executed,
+            status: self.status,
+        }
+    }
+
+    pub fn restore(&mut self, state: VmState) {
+        self.memory = state.memory;
+        self.ret = state.ret;
+        self.ip = state.ip;
+        self.fp = state.fp;
+        [self.a, self.b, self.c, self.d] = state.registers;
+        self.executed = state.executed;
+        self.status = state.status;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.status.is_some()
+    }
+
+    pub fn arg_offset(index: u32) -> Offset {
+        -2 - index as Offset
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn labels(&self) -> &HashMap<String, Address> {
+        &self.labels
+    }
+
+    pub fn constants(&self) -> &ConstantPool {
+        &self.constants
+    }
+
+    pub fn memory(&self) -> &[u32] {
+        &self.memory
+    }
+
+    pub fn call_stack(&self) -> &[u32] {
+        &self.ret
+    }
+
+    pub fn ip(&self) -> Address {
+        self.ip
+    }
+
+    pub fn fp(&self) -> Address {
+        self.fp
+    }
+
+    // Record every executed instruction to the sink
+    pub fn set_trace(&mut self, sink: Box<dyn TraceSink>) {
+        self.tracer = Some(Tracer::new(sink, &self.labels));
+    }
+
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new(&self.labels, self.instructions.len()));
+    }
+
+    pub fn take_profile(&mut self) -> Option<Profiler> {
+        let mut profiler = self.profiler.take()?;
+        profiler.finish();
+        Some(profiler)
+    }
+
+    // Keeps what the program writes to stdout and stderr instead of printing
+    // it, so tests can check what a program printed
+    #[cfg(test)]
+    pub fn capture_output(&mut self) {
+        self.output = Some(vec![]);
+    }
+
+    #[cfg(test)]
+    pub fn take_output(&mut self) -> Option<Vec<u8>> {
+        self.output.take()
+    }
+
+    pub fn set_source_lines(&mut self, lines: Vec<usize>) {
+        self.source_lines = lines;
+    }
+
+    pub fn source_line(&self, address: Address) -> Option<usize> {
+        self.source_lines.get(address as usize).copied()
+    }
+
+    // First instruction generated from the line, or from the closest line
+    // after it
+    pub fn address_of_line(&self, line: usize) -> Option<Address> {
+        self.source_lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| **l >= line)
+            .min_by_key(|(address, l)| (**l, *address))
+            .map(|(address, _)| address as Address)
+    }
+
+    // Words are 32 bits, so integer constants have to fit in an i32 once they
+    // are folded. Arithmetic on them wraps at 32 bits instead of 64 like the
+    // other backends. Strings end with a NUL byte, so they can't hold one.
+    fn check_words(module: &Module) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        for function in std::iter::once(&module.entry).chain(&module.procs) {
+            for instruction in function.blocks.iter().flat_map(|block| &block.instructions) {
+                let ir::Instruction::Const(temp, value) = instruction else {
+                    continue;
+                };
+                let origin = &function.origins[*temp];
+                let context = origin.context();
+                let message = match value {
+                    Value::Int(int) if i32::try_from(*int).is_err() => {
+                        format!("integer {int} {context} does not fit in a 32 bit VM word")
                     }
-                }
-                Item::Expression(expr) => {
-                    match expr {
-                        Expression::Unary(unary) => {
-                            match unary {
-                                Unary::Call(call) => {
-                                    match call {
-                                        Call::Primary(primary) => {
-                                            match primary {
-                                                Primary::True => {
-                                                    todo!()
-                                                }
-                                                Primary::False => {
-                                                    todo!()
-                                                }
-                                                Primary::Null => {
-                                                    todo!()
-                                                }
-                                                Primary::Float(float) => {
-                                                    todo!()
-                                                }
-                                                Primary::Int(int) => {
-                                                    todo!()
-                                                }
-                                                Primary::String(string) => {
-                                                    todo!()
-                                                }
-                                                Primary::Identifier(ident) => {
-                                                    todo!()
-                                                }
-                                                Primary::Return(expr) => {
-                                                    todo!()
-                                                }
-                                                Primary::Expression(expr) => {
-                                                    todo!()
-                                                }
-                                            }
-                                        }
-                                        Call::CallLiteral {identifier, call_args } => {
-                                            todo!()
-                                        }
-                                    }
-                                }
-                                Unary::UnaryOperation { unary, operator} => {
-                                    todo!()
-                                }
-                            }
-                        }
-                        Expression::Binary(binary) => {
-                            let Binary { operator, left, right} = binary;
-                            todo!()
-                        }
+                    Value::String(string) if string.contains('\0') => {
+                        format!("string {context} holds a NUL byte, which ends strings on the VM")
                     }
-                }
+                    _ => continue,
+                };
+                diagnostics.push(Diagnostic::error(message).at(origin.span));
             }
         }
-        todo!()
+        diagnostics
+    }
+
+    // Compiles a program through the IR. Top level code runs in the entry
+    // frame at address 0 and its locals are the globals. That frame is opened
+    // right above the data segment, so every global has a fixed address once
+    // the constants are known.
+    //
+    // Words don't say what they hold, so every value needs a type known when
+    // compiling, see value_types. Arithmetic is only done on ints.
+    pub fn from_ir(module: &Module) -> Result<Self, Vec<Diagnostic>> {
+        let diagnostics = VM::check_words(module);
+        let types = value_types::infer(module, "VM", |_, left, right| match (left, right) {
+            (Type::Int, Type::Int) => Some(Type::Int),
+            _ => None,
+        });
+        let types = match types {
+            Ok(types) if diagnostics.is_empty() => types,
+            Ok(_) => return Err(diagnostics),
+            Err(errors) => return Err([diagnostics, errors].concat()),
+        };
+
+        let mut instructions = vec![];
+        let mut labels = vec![];
+        let mut constants = ConstantPool::new();
+        let mut jumps = vec![];
+        let mut tail_calls = vec![];
+
+        // LW and SW are only used for globals, which are numbered from 0
+        // until the size of the data segment is known
+        let globals: HashMap<Variable, Location> = module
+            .globals
+            .iter()
+            .enumerate()
+            .map(|(slot, name)| (Variable::Global(name.clone()), Location::Global(slot as Address)))
+            .collect();
+
+        for function in std::iter::once(&module.entry).chain(&module.procs) {
+            let mut scope = globals.clone();
+            for (index, arg) in function.params.iter().enumerate() {
+                scope.insert(Variable::Local(arg.clone()), Location::Frame(VM::arg_offset(index as u32)));
+            }
+            // The entry frame holds the globals before its locals
+            let base = if function.name.is_empty() { module.globals.len() } else { 0 };
+            for (slot, local) in function.locals.iter().enumerate() {
+                scope.insert(Variable::Local(local.clone()), Location::Frame((base + slot) as Offset));
+            }
+
+            let locals = (base + function.locals.len()) as u32;
+            let allocation = register_allocator::allocate(function);
+            if !function.name.is_empty() {
+                labels.push((function.name.clone(), instructions.len() as Address));
+            }
+            instructions.push(Instruction::Enter(locals + allocation.spills));
+
+            let mut blocks = vec![];
+            let start = jumps.len();
+            FunctionLowering {
+                instructions: &mut instructions,
+                constants: &mut constants,
+                jumps: &mut jumps,
+                tail_calls: &mut tail_calls,
+                scope: &scope,
+                allocation: &allocation,
+                types: &types,
+                locals,
+                args: function.params.len() as u32,
+            }
+            .function(function, &mut blocks);
+
+            for (jump, block) in &jumps[start..] {
+                instructions[*jump] = Instruction::Jump(blocks[*block]);
+            }
+        }
+
+        // Tail calls jump straight to the callee, so they are linked here
+        // instead of by load
+        let mut errors = vec![];
+        for (jump, identifier) in &tail_calls {
+            match labels.iter().find(|(label, _)| label == identifier) {
+                Some((_, address)) => instructions[*jump] = Instruction::Jump(*address),
+                None => errors.push(LinkError::UndefinedLabel(identifier.clone())),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.iter().map(|error| Diagnostic::error(error.to_string())).collect());
+        }
+
+        let base = constants.data_segment().len() as Address + 1;
+        for instruction in &mut instructions {
+            if let Instruction::LW(_, address) | Instruction::SW(_, address) = instruction {
+                *address += base;
+            }
+        }
+
+        let (instructions, labels) = peephole::optimize(instructions, labels);
+        VM::load(instructions, labels, constants)
+            .map_err(|errors| errors.iter().map(|error| Diagnostic::error(error.to_string())).collect())
     }
 
     pub fn next_instruction(&mut self) -> Option<Instruction> {
@@ -169,92 +705,483 @@ impl VM {
 
         let instruction = self.instructions[self.ip as usize].clone();
         self.ip += 1;
-        return Some(instruction);
+        Some(instruction)
     }
 
-    pub fn run(&mut self) {
-        loop {
-            let instruction = self.next_instruction();
+    fn resolved(target: &Target) -> Result<Address, VmErrorKind> {
+        match target {
+            Target::Address(address) => Ok(*address),
+            Target::Label(label) => Err(VmErrorKind::UnknownLabel(label.clone())),
+        }
+    }
+
+    pub fn registers(&self) -> [u32; 4] {
+        [self.a, self.b, self.c, self.d]
+    }
 
-            if instruction.is_none() {
-                break;
+    pub fn register(&self, register: Register) -> u32 {
+        match register {
+            Register::A => self.a,
+            Register::B => self.b,
+            Register::C => self.c,
+            Register::D => self.d,
+        }
+    }
+
+    fn set_register(&mut self, register: Register, value: u32) {
+        match register {
+            Register::A => self.a = value,
+            Register::B => self.b = value,
+            Register::C => self.c = value,
+            Register::D => self.d = value,
+        }
+    }
+
+    fn load_word(&self, address: Address) -> Result<u32, VmErrorKind> {
+        match self.memory.get(address as usize) {
+            Some(&word) => Ok(word),
+            None => Err(VmErrorKind::BadAddress(address)),
+        }
+    }
+
+    fn store_word(&mut self, address: Address, value: u32) -> Result<(), VmErrorKind> {
+        match self.memory.get_mut(address as usize) {
+            Some(word) => {
+                *word = value;
+                Ok(())
             }
+            None => Err(VmErrorKind::BadAddress(address)),
+        }
+    }
 
-            match instruction.unwrap() {
-                Instruction::Noop => {},
-                Instruction::Ret => {
-                    let return_value = self.ret.pop();
+    fn frame_address(&self, offset: Offset) -> Result<Address, VmErrorKind> {
+        let address = self.fp as i64 + offset as i64;
+        if address < 0 {
+            return Err(VmErrorKind::BadAddress(0));
+        }
+        Ok(address as Address)
+    }
 
-                    match return_value {
-                        Some(return_value) => self.ip = return_value,
-                        None => break
-                    }
-                },
-                // _ => todo!()
-                Instruction::Move(to, value) => {
-                    match to {
-                        Register::A => self.a = value,
-                        Register::B => self.b = value,
-                        Register::C => self.c = value,
-                        Register::D => self.d = value,
+    // Checked before memory grows, so operands like the locals of an Enter
+    // can't make the host allocate more than the limit
+    fn reserve(&self, words: usize) -> Result<(), VmErrorKind> {
+        if self.memory.len() + words > self.limits.memory {
+            return Err(VmErrorKind::MemoryLimitExceeded);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: u32) -> Result<(), VmErrorKind> {
+        self.reserve(1)?;
+        self.memory.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u32, VmErrorKind> {
+        self.memory.pop().ok_or(VmErrorKind::StackUnderflow)
+    }
+
+    // Bytes are packed little endian, WORD_SIZE to a word
+    fn read_bytes(&self, buf_pointer: Address, count: u32) -> Result<Vec<u8>, VmErrorKind> {
+        let start = buf_pointer as usize;
+        let end = start + (count as usize).div_ceil(WORD_SIZE);
+        if end > self.memory.len() {
+            return Err(VmErrorKind::BadAddress(self.memory.len().max(start) as Address));
+        }
+
+        let mut bytes: Vec<u8> = self.memory[start..end]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        bytes.truncate(count as usize);
+        Ok(bytes)
+    }
+
+    fn write_bytes(&mut self, buf_pointer: Address, bytes: &[u8]) -> Result<(), VmErrorKind> {
+        for (i, chunk) in bytes.chunks(WORD_SIZE).enumerate() {
+            let mut word = [0; WORD_SIZE];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.store_word(buf_pointer + i as Address, u32::from_le_bytes(word))?;
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, file_descriptor: u32, bytes: &[u8]) -> Result<(), VmErrorKind> {
+        if let (STDOUT | STDERR, Some(output)) = (file_descriptor, &mut self.output) {
+            output.extend_from_slice(bytes);
+            return Ok(());
+        }
+        let result = match file_descriptor {
+            STDOUT => std::io::stdout().write_all(bytes),
+            STDERR => std::io::stderr().write_all(bytes),
+            _ => return Err(VmErrorKind::BadFileDescriptor(file_descriptor)),
+        };
+        result.map_err(|error| VmErrorKind::Io(error.kind()))
+    }
+
+    fn write_float(&mut self, file_descriptor: u32, buf_pointer: Address) -> Result<(), VmErrorKind> {
+        let low = self.load_word(buf_pointer)? as u64;
+        let high = self.load_word(buf_pointer.wrapping_add(1))? as u64;
+        let float = f64::from_bits(high << 32 | low);
+        self.output(file_descriptor, float.to_string().as_bytes())
+    }
+
+    fn syscall(&mut self, syscall: SysCall) -> Result<(), VmErrorKind> {
+        match syscall {
+            SysCall::Read { file_descriptor, buf_pointer, count } => {
+                if file_descriptor != STDIN {
+                    return Err(VmErrorKind::BadFileDescriptor(file_descriptor));
+                }
+
+                // The buffer is in memory, so it can't be larger than it
+                if (count as usize).div_ceil(WORD_SIZE) > self.limits.memory {
+                    return Err(VmErrorKind::MemoryLimitExceeded);
+                }
+                let mut bytes = vec![0; count as usize];
+                let read = std::io::stdin()
+                    .read(&mut bytes)
+                    .map_err(|error| VmErrorKind::Io(error.kind()))?;
+                self.write_bytes(buf_pointer, &bytes[..read])?;
+                self.a = read as u32;
+            }
+            SysCall::Write { file_descriptor, buf_pointer, count } => {
+                let bytes = self.read_bytes(buf_pointer, count)?;
+                self.output(file_descriptor, &bytes)?;
+            }
+            SysCall::WriteFloat { file_descriptor, buf_pointer } => self.write_float(file_descriptor, buf_pointer)?,
+            SysCall::WriteInt { file_descriptor, register } => {
+                let value = self.register(register) as i32;
+                self.output(file_descriptor, value.to_string().as_bytes())?;
+            }
+            SysCall::WriteBool { file_descriptor, register } => {
+                let value = self.register(register) != 0;
+                self.output(file_descriptor, value.to_string().as_bytes())?;
+            }
+            SysCall::WriteString { file_descriptor, register } => {
+                let mut bytes = vec![];
+                let mut address = self.register(register);
+                loop {
+                    let word = self.load_word(address)?.to_le_bytes();
+                    match word.iter().position(|byte| *byte == 0) {
+                        Some(end) => {
+                            bytes.extend_from_slice(&word[..end]);
+                            break;
+                        }
+                        None => bytes.extend_from_slice(&word),
                     }
+                    address = address.wrapping_add(1);
                 }
-                Instruction::Jump(address) => {
-                    self.ip = address;
+                self.output(file_descriptor, &bytes)?;
+            }
+            SysCall::WriteFloatR { file_descriptor, register } => {
+                self.write_float(file_descriptor, self.register(register))?
+            }
+        }
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, instruction: &Instruction) -> Result<(), VmErrorKind> {
+        let (to, from) = match instruction {
+            | Instruction::Add(to, from)
+            | Instruction::Sub(to, from)
+            | Instruction::Mul(to, from)
+            | Instruction::Div(to, from) => (*to, *from),
+            _ => unreachable!(),
+        };
+
+        let a = self.register(to);
+        let b = self.register(from);
+        let result = match instruction {
+            Instruction::Add(..) => a.wrapping_add(b),
+            Instruction::Sub(..) => a.wrapping_sub(b),
+            Instruction::Mul(..) => a.wrapping_mul(b),
+            _ => {
+                if b == 0 {
+                    return Err(VmErrorKind::DivisionByZero);
                 }
-                Instruction::Call(call_addr) => {
-                    self.ret.push(self.ip);
-                    self.ip = self.labels[&call_addr];
+                (a as i32).wrapping_div(b as i32) as u32
+            }
+        };
+        self.set_register(to, result);
+        Ok(())
+    }
+
+    // Jumping to the end of the program stops it like running past the last
+    // instruction does, anything after that is an error
+    fn jump(&mut self, address: Address) -> Result<(), VmErrorKind> {
+        if address as usize > self.instructions.len() {
+            return Err(VmErrorKind::BadJumpTarget(address));
+        }
+        self.ip = address;
+        Ok(())
+    }
+
+    // Returns the exit status once the program has stopped
+    fn execute(&mut self, instruction: &Instruction) -> Result<Option<ExitStatus>, VmErrorKind> {
+        match instruction {
+            Instruction::Noop => {},
+            Instruction::Ret => {
+                let return_value = self.ret.pop();
+
+                match return_value {
+                    Some(return_value) => self.ip = return_value,
+                    None => return Ok(Some(ExitStatus::Returned(self.a)))
                 }
-                Instruction::JEQ(a, b, address) => {
-                    if a == b {
-                        self.ip = address;
-                    }
+            },
+            Instruction::Move(to, value) => self.set_register(*to, *value),
+            Instruction::Copy(to, from) => self.set_register(*to, self.register(*from)),
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Div(..) => self.arithmetic(instruction)?,
+            Instruction::Not(register) => self.set_register(*register, (self.register(*register) == 0) as u32),
+            Instruction::Jump(address) => self.jump(*address)?,
+            Instruction::Call(target) => {
+                if self.ret.len() >= self.limits.call_depth {
+                    return Err(VmErrorKind::StackOverflow);
                 }
-                Instruction::JGT(a, b, address) => {
-                    if a > b {
-                        self.ip = address;
-                    }
+
+                let address = VM::resolved(target)?;
+                let ip = self.ip;
+                self.jump(address)?;
+                self.ret.push(ip);
+            }
+            Instruction::JEQ(a, b, address) => {
+                if a == b {
+                    self.jump(*address)?;
                 }
-                Instruction::JGE(a, b, address) => {
-                    if a >= b {
-                        self.ip = address;
-                    }
+            }
+            Instruction::JGT(a, b, address) => {
+                if a > b {
+                    self.jump(*address)?;
                 }
-                Instruction::LA(register, label) => {
-                    match register {
-                        Register::A => self.a = self.labels[&label],
-                        Register::B => self.b = self.labels[&label],
-                        Register::C => self.c = self.labels[&label],
-                        Register::D => self.d = self.labels[&label],
-                    }
+            }
+            Instruction::JGE(a, b, address) => {
+                if a >= b {
+                    self.jump(*address)?;
                 }
-                Instruction::LW(register, address) => {
-                    match register {
-                        Register::A => self.a = self.memory[address as usize],
-                        Register::B => self.b = self.memory[address as usize],
-                        Register::C => self.c = self.memory[address as usize],
-                        Register::D => self.d = self.memory[address as usize]
-                    }
+            }
+            Instruction::LA(register, target) => {
+                self.set_register(*register, VM::resolved(target)?);
+            }
+            Instruction::LW(register, address) => {
+                self.set_register(*register, self.load_word(*address)?);
+            }
+            Instruction::SW(register, address) => {
+                self.store_word(*address, self.register(*register))?;
+            }
+            Instruction::LC(register, index) => {
+                if *index as usize >= self.constants.len() {
+                    return Err(VmErrorKind::BadConstant(*index));
                 }
-                Instruction::SW(register, address) => {
-                    match register {
-                        Register::A => self.memory[address as usize] = self.a,
-                        Register::B => self.memory[address as usize] = self.b,
-                        Register::C => self.memory[address as usize] = self.c,
-                        Register::D => self.memory[address as usize] = self.d
-                    }
+                let address = self.constants.address(*index);
+                self.set_register(*register, address);
+            }
+            Instruction::LR(to, from) => {
+                self.set_register(*to, self.load_word(self.register(*from))?);
+            }
+            Instruction::Push(value) => self.push(*value)?,
+            Instruction::PushR(register) => self.push(self.register(*register))?,
+            Instruction::Pop(register) => {
+                let value = self.pop()?;
+                self.set_register(*register, value);
+            }
+            Instruction::Enter(locals) => {
+                self.reserve(1 + *locals as usize)?;
+                self.memory.push(self.fp);
+                self.fp = self.memory.len() as u32;
+                self.memory.resize(self.memory.len() + *locals as usize, 0);
+            }
+            Instruction::Leave(args) => {
+                if self.fp as usize > self.memory.len() {
+                    return Err(VmErrorKind::BadAddress(self.fp));
                 }
-                Instruction::Push(value) => self.memory.push(value),
-                Instruction::Pop(register) => {
-                    match register {
-                        Register::A => self.a = self.memory.pop().unwrap(),
-                        Register::B => self.b = self.memory.pop().unwrap(),
-                        Register::C => self.c = self.memory.pop().unwrap(),
-                        Register::D => self.d = self.memory.pop().unwrap()
-                    }
+                self.memory.truncate(self.fp as usize);
+                self.fp = self.pop()?;
+
+                let args = *args as usize;
+                if args > self.memory.len() {
+                    return Err(VmErrorKind::StackUnderflow);
                 }
+                self.memory.truncate(self.memory.len() - args);
+            }
+            Instruction::LF(register, offset) => {
+                let value = self.load_word(self.frame_address(*offset)?)?;
+                self.set_register(*register, value);
+            }
+            Instruction::SF(register, offset) => {
+                self.store_word(self.frame_address(*offset)?, self.register(*register))?;
+            }
+            Instruction::SysCall(syscall) => self.syscall(*syscall)?,
+        }
+        Ok(None)
+    }
+
+    // Executes a single instruction, returning the exit status if the
+    // program stopped
+    pub fn step(&mut self) -> Result<Option<ExitStatus>, VmError> {
+        if self.status.is_some() {
+            return Ok(self.status);
+        }
+
+        let ip = self.ip;
+
+        if self.limits.instructions.is_some_and(|limit| self.executed >= limit) {
+            if let Some(instruction) = self.instructions.get(ip as usize) {
+                return Err(VmError {
+                    ip,
+                    instruction: instruction.clone(),
+                    kind: VmErrorKind::InstructionBudgetExceeded,
+                });
             }
         }
+
+        let instruction = match self.next_instruction() {
+            Some(instruction) => instruction,
+            None => {
+                self.status = Some(ExitStatus::Halted);
+                return Ok(self.status);
+            }
+        };
+        self.executed += 1;
+
+        let before = self.registers();
+        let mut result = self.execute(&instruction);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(ip, &instruction, self.ip, self.ret.len());
+        }
+
+        let after = self.registers();
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(error) = tracer.record(ip, &instruction, before, after) {
+                result = result.and(Err(VmErrorKind::Io(error.kind())));
+            }
+        }
+
+        if let Ok(Some(status)) = result {
+            self.status = Some(status);
+        }
+
+        result.map_err(|kind| VmError {
+            ip,
+            instruction: instruction.clone(),
+            kind,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        loop {
+            if let Some(status) = self.step()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    // Runs at most `instructions` instructions and returns Paused if the
+    // program hasn't stopped by then. Calling it again resumes where it left
+    // off, so a host can time slice several VMs on one thread.
+    pub fn run_for(&mut self, instructions: u64) -> Result<ExitStatus, VmError> {
+        for _ in 0..instructions {
+            if let Some(status) = self.step()? {
+                return Ok(status);
+            }
+        }
+
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        Ok(ExitStatus::Paused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn fail(source: &str, limits: Limits) -> (VM, VmError) {
+        let mut vm = assemble(source).unwrap();
+        vm.set_limits(limits);
+        let error = vm.run().unwrap_err();
+        (vm, error)
+    }
+
+    #[test]
+    fn stops_at_the_instruction_budget() {
+        let limits = Limits { instructions: Some(100), ..Limits::default() };
+        let (vm, error) = fail("main:\n jump main", limits);
+        assert!(matches!(error.kind, VmErrorKind::InstructionBudgetExceeded));
+        assert_eq!(vm.executed(), 100);
+    }
+
+    #[test]
+    fn stops_at_the_memory_limit() {
+        let limits = Limits { memory: 64, ..Limits::default() };
+        let (vm, error) = fail("main:\n push 1\n jump main", limits);
+        assert!(matches!(error.kind, VmErrorKind::MemoryLimitExceeded));
+        assert_eq!(vm.memory().len(), 64);
+    }
+
+    // The default limit is checked before anything is allocated
+    #[test]
+    fn rejects_huge_enter_and_read_operands() {
+        let (vm, error) = fail("enter 4294967295", Limits::default());
+        assert!(matches!(error.kind, VmErrorKind::MemoryLimitExceeded));
+        assert!(vm.memory().is_empty());
+
+        let (_, error) = fail(".string \"buffer\"\n syscall read 0 0 4294967295", Limits::default());
+        assert!(matches!(error.kind, VmErrorKind::MemoryLimitExceeded));
+    }
+
+    #[test]
+    fn stops_at_the_call_depth() {
+        let limits = Limits { call_depth: 10, ..Limits::default() };
+        let (vm, error) = fail("main:\n call main", limits);
+        assert!(matches!(error.kind, VmErrorKind::StackOverflow));
+        assert_eq!(vm.call_stack().len(), 10);
+    }
+
+    #[test]
+    fn reports_the_faulting_instruction() {
+        let error = |source: &str| fail(source, Limits::default()).1;
+
+        let underflow = error("main:\n move a 1\n pop a");
+        assert!(matches!(underflow.kind, VmErrorKind::StackUnderflow));
+        assert_eq!(underflow.ip, 1);
+        assert!(matches!(underflow.instruction, Instruction::Pop(Register::A)));
+
+        let bad_address = error("main:\n lw a 1000");
+        assert!(matches!(bad_address.kind, VmErrorKind::BadAddress(1000)));
+        assert_eq!(bad_address.ip, 0);
+
+        let division = error("main:\n move a 1\n move b 0\n div a b");
+        assert!(matches!(division.kind, VmErrorKind::DivisionByZero));
+        assert_eq!(division.ip, 2);
+
+        let jump = error("main:\n noop\n jump 99");
+        assert!(matches!(jump.kind, VmErrorKind::BadJumpTarget(99)));
+        assert_eq!(jump.ip, 1);
+
+        let call = error("main:\n call 99");
+        assert!(matches!(call.kind, VmErrorKind::BadJumpTarget(99)));
+        assert_eq!(call.ip, 0);
+    }
+
+    // Loading links every label, so only a VM built without it can run into
+    // one
+    #[test]
+    fn reports_unknown_labels() {
+        let mut vm = VM::new();
+        vm.instructions = vec![Instruction::Noop, Instruction::Call(Target::Label("missing".to_string()))];
+        let error = vm.run().unwrap_err();
+        assert!(matches!(error.kind, VmErrorKind::UnknownLabel(ref label) if label == "missing"));
+        assert_eq!(error.ip, 1);
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_program() {
+        let mut vm = assemble("main:\n jump end\n noop\n end:").unwrap();
+        assert_eq!(vm.run().unwrap(), ExitStatus::Halted);
     }
 }
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use crate::ast::{BinaryOperator, Type};
+use crate::diagnostic::Diagnostic;
+use crate::ir::{self, BlockId, Instruction, Module, Temp, Terminator, Value, Variable};
+use crate::value_types::{self, Types};
+
+const PAGE_SIZE: u32 = 65536;
+
+// Functions the host has to provide. Printing is in the "host" module, the
+// exit status goes to WASI's proc_exit.
+const IMPORTS: &str = r#"  (import "host" "print_int" (func $print_int (param i64)))
+  (import "host" "print_float" (func $print_float (param f64)))
+  (import "host" "print_string" (func $print_string (param i32 i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+"#;
+
+// Division that wraps like i64 in Rust instead of trapping on MIN / -1,
+// division by zero still traps
+const RUNTIME: &str = r#"  (func $rt_div (param $a i64) (param $b i64) (result i64)
+    local.get $b
+    i64.const -1
+    i64.eq
+    if
+      i64.const 0
+      local.get $a
+      i64.sub
+      return
+    end
+    local.get $a
+    local.get $b
+    i64.div_s)
+  (func $rt_print_bool (param $value i64)
+    local.get $value
+    i64.eqz
+    if
+      i32.const 0
+      i32.const 5
+      call $print_string
+    else
+      i32.const 5
+      i32.const 4
+      call $print_string
+    end)
+  (func $rt_print_string (param $string i64)
+    local.get $string
+    i32.wrap_i64
+    i32.const 4
+    i32.add
+    local.get $string
+    i32.wrap_i64
+    i32.load
+    call $print_string)
+  ;; Strings are never freed, the heap only grows
+  (func $rt_concat (param $a i64) (param $b i64) (result i64)
+    (local $left i32) (local $right i32) (local $string i32) (local $end i32)
+    local.get $a
+    i32.wrap_i64
+    i32.load
+    local.set $left
+    local.get $b
+    i32.wrap_i64
+    i32.load
+    local.set $right
+    global.get $rt_heap
+    local.set $string
+    local.get $string
+    i32.const 4
+    i32.add
+    local.get $left
+    i32.add
+    local.get $right
+    i32.add
+    local.set $end
+    local.get $end
+    memory.size
+    i32.const 65536
+    i32.mul
+    i32.gt_u
+    if
+      local.get $end
+      i32.const 65535
+      i32.add
+      i32.const 65536
+      i32.div_u
+      memory.size
+      i32.sub
+      memory.grow
+      i32.const -1
+      i32.eq
+      if
+        unreachable
+      end
+    end
+    local.get $end
+    global.set $rt_heap
+    local.get $string
+    local.get $left
+    local.get $right
+    i32.add
+    i32.store
+    local.get $string
+    i32.const 4
+    i32.add
+    local.get $a
+    i32.wrap_i64
+    i32.const 4
+    i32.add
+    local.get $left
+    memory.copy
+    local.get $string
+    i32.const 4
+    i32.add
+    local.get $left
+    i32.add
+    local.get $b
+    i32.wrap_i64
+    i32.const 4
+    i32.add
+    local.get $right
+    memory.copy
+    local.get $string
+    i64.extend_i32_u)
+"#;
+
+// The text rt_print_bool prints, at address 0. Null is told apart from
+// strings by its type, so no address has to be kept free for it.
+const BOOLS: &str = "falsetrue";
+
+// The type of values of a static type
+fn value_type(value: Type) -> &'static str {
+    match value {
+        Type::Float => "f64",
+        _ => "i64",
+    }
+}
+
+// What the backend computes, arithmetic on numbers like in the C runtime and
+// + on two strings
+fn arithmetic(operator: &BinaryOperator, left: Type, right: Type) -> Option<Type> {
+    match (left, right) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Int | Type::Float, Type::Int | Type::Float) => Some(Type::Float),
+        (Type::String, Type::String) if matches!(operator, BinaryOperator::Plus) => Some(Type::String),
+        _ => None,
+    }
+}
+
+// Compiles a program to the WebAssembly text format. Procs become functions
+// and the top level code becomes the exported _start function, which takes
+// and returns nothing like WASI expects. It calls main and passes what that
+// returns to proc_exit if it is an int. Memory is exported as "memory".
+//
+// Every value has a type known when compiling, see value_types. Floats are
+// f64, everything else is i64: ints are themselves, true is 1, false and null
+// are 0, and strings are the address of their length, an i32, followed by
+// their bytes. println prints each value by its type.
+//
+// IR temps become locals. Jumps only go forward, so every jump target gets a
+// `block` that ends right before it and jumps are `br` out of it.
+pub fn generate_code(module: &Module) -> Result<String, Vec<Diagnostic>> {
+    let types = value_types::infer(module, "wasm", arithmetic)?;
+    let mut data = Data::new();
+    let mut functions = String::new();
+
+    for proc in &module.procs {
+        functions += &format!("  (func $p_{}", proc.name);
+        for arg in &proc.params {
+            let value = types.variable(proc, &Variable::Local(arg.clone()));
+            functions += &format!(" (param $v_{arg} {})", value_type(value));
+        }
+        functions += &format!(" (result {})\n", value_type(types.returns(proc)));
+        functions += &locals(proc, &types);
+        functions += &Function::new(proc, &types, &mut data).function(proc);
+        functions += "  )\n";
+    }
+
+    functions += "  (func $_start (export \"_start\")\n";
+    functions += &locals(&module.entry, &types);
+    functions += &Function::new(&module.entry, &types, &mut data).function(&module.entry);
+    functions += "  )\n";
+
+    let mut output = String::from("(module\n");
+    output += IMPORTS;
+    output += &format!("  (memory (export \"memory\") {})\n", data.end.div_ceil(PAGE_SIZE).max(1));
+    output += &data.segments;
+    for name in &module.globals {
+        let value = value_type(types.variable(&module.entry, &Variable::Global(name.clone())));
+        output += &format!("  (global $v_{name} (mut {value}) ({value}.const 0))\n");
+    }
+    // Strings made at run time go after the literals
+    output += &format!("  (global $rt_heap (mut i32) (i32.const {}))\n", data.end);
+    output += RUNTIME;
+    output += &functions;
+    output += ")\n";
+    Ok(output)
+}
+
+fn locals(function: &ir::Function, types: &Types) -> String {
+    let mut locals = String::new();
+    for local in &function.locals {
+        let value = types.variable(function, &Variable::Local(local.clone()));
+        locals += &format!("    (local $v_{local} {})\n", value_type(value));
+    }
+    locals
+}
+
+// WAT float literal, `{:?}` writes NaN and inf, which WAT spells differently
+fn float_literal(float: f64) -> String {
+    match float {
+        float if float.is_nan() => "nan".to_string(),
+        f64::INFINITY => "inf".to_string(),
+        f64::NEG_INFINITY => "-inf".to_string(),
+        float => format!("{float:?}"),
+    }
+}
+
+// Literals laid out in memory in the order they are first used
+struct Data {
+    segments: String,
+    strings: HashMap<String, u32>,
+    end: u32,
+}
+
+impl Data {
+    fn new() -> Self {
+        let mut data = Data {
+            segments: String::new(),
+            strings: HashMap::new(),
+            end: 0,
+        };
+        data.add(BOOLS.as_bytes(), 1);
+        data
+    }
+
+    fn add(&mut self, bytes: &[u8], align: u32) -> u32 {
+        let address = self.end.next_multiple_of(align);
+        self.segments += &format!("  (data (i32.const {address}) {})\n", quote(bytes));
+        self.end = address + bytes.len() as u32;
+        address
+    }
+
+    // The address of the length, the bytes follow it
+    fn string(&mut self, string: &str) -> u32 {
+        if let Some(address) = self.strings.get(string) {
+            return *address;
+        }
+        let mut bytes = (string.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(string.as_bytes());
+        let address = self.add(&bytes, 4);
+        self.strings.insert(string.to_string(), address);
+        address
+    }
+}
+
+struct Function<'a> {
+    body: String,
+    function: &'a ir::Function,
+    types: &'a Types,
+    definitions: HashMap<Temp, &'a Instruction>,
+    uses: Vec<usize>,
+    data: &'a mut Data,
+}
+
+impl<'a> Function<'a> {
+    fn new(function: &'a ir::Function, types: &'a Types, data: &'a mut Data) -> Self {
+        Function {
+            body: String::new(),
+            function,
+            types,
+            definitions: function.definitions(),
+            uses: function.use_counts(),
+            data,
+        }
+    }
+
+    fn type_of(&self, temp: Temp) -> Type {
+        self.types.temp(self.function, temp)
+    }
+
+    fn line(&mut self, line: &str) {
+        self.body += "    ";
+        self.body += line;
+        self.body += "\n";
+    }
+
+    // Returns the locals for the temps followed by the body
+    fn function(mut self, function: &ir::Function) -> String {
+        let mut targets: Vec<BlockId> = function.targets().into_iter().collect();
+        targets.sort();
+        for target in targets.iter().rev() {
+            self.line(&format!("block $b{target}"));
+        }
+
+        for (id, block) in function.blocks.iter().enumerate() {
+            if targets.contains(&id) {
+                self.line("end");
+            }
+
+            for instruction in &block.instructions {
+                self.instruction(instruction);
+            }
+
+            match block.terminator {
+                // Only ints are exit statuses
+                Terminator::Return(temp) if function.name.is_empty() => {
+                    if self.types.returns(function) == Type::Int {
+                        self.get(temp);
+                        self.line("i32.wrap_i64");
+                        self.line("call $proc_exit");
+                    }
+                    self.line("return");
+                }
+                Terminator::Return(temp) => {
+                    self.get(temp);
+                    self.line("return");
+                }
+                Terminator::Jump(target) => self.line(&format!("br $b{target}")),
+            }
+        }
+
+        let mut locals = String::new();
+        for temp in 0..function.temps {
+            if self.uses[temp] > 0 && !matches!(self.definitions.get(&temp), Some(Instruction::Const(..))) {
+                locals += &format!("    (local $t{temp} {})\n", value_type(self.type_of(temp)));
+            }
+        }
+        locals + &self.body
+    }
+
+    // Pushes the temp, constants are written where they are used
+    fn get(&mut self, temp: Temp) {
+        let line = match self.definitions.get(&temp) {
+            Some(Instruction::Const(_, Value::Null | Value::Bool(false))) => "i64.const 0".to_string(),
+            Some(Instruction::Const(_, Value::Bool(true))) => "i64.const 1".to_string(),
+            Some(Instruction::Const(_, Value::Int(int))) => format!("i64.const {int}"),
+            Some(Instruction::Const(_, Value::Float(float))) => format!("f64.const {}", float_literal(*float)),
+            Some(Instruction::Const(_, Value::String(string))) => format!("i64.const {}", self.data.string(string)),
+            _ => format!("local.get $t{temp}"),
+        };
+        self.line(&line);
+    }
+
+    // Pops the value of the temp, dropping it if nothing uses it
+    fn set(&mut self, temp: Temp) {
+        if self.uses[temp] > 0 {
+            self.line(&format!("local.set $t{temp}"));
+        } else {
+            self.line("drop");
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Const(..) => {}
+            Instruction::Load(temp, variable) => {
+                match variable {
+                    Variable::Local(name) => self.line(&format!("local.get $v_{name}")),
+                    Variable::Global(name) => self.line(&format!("global.get $v_{name}")),
+                }
+                self.set(*temp);
+            }
+            Instruction::Store(variable, temp) => {
+                self.get(*temp);
+                match variable {
+                    Variable::Local(name) => self.line(&format!("local.set $v_{name}")),
+                    Variable::Global(name) => self.line(&format!("global.set $v_{name}")),
+                }
+            }
+            Instruction::Binary(temp, operator, left, right) => {
+                let result = self.type_of(*temp);
+                for operand in [*left, *right] {
+                    self.get(operand);
+                    // An int operand of float arithmetic is converted
+                    if result == Type::Float && self.type_of(operand) == Type::Int {
+                        self.line("f64.convert_i64_s");
+                    }
+                }
+                let instruction = match (result, operator) {
+                    (Type::String, _) => "call $rt_concat",
+                    (Type::Float, BinaryOperator::Plus) => "f64.add",
+                    (Type::Float, BinaryOperator::Minus) => "f64.sub",
+                    (Type::Float, BinaryOperator::Multiply) => "f64.mul",
+                    (Type::Float, BinaryOperator::Divide) => "f64.div",
+                    (_, BinaryOperator::Plus) => "i64.add",
+                    (_, BinaryOperator::Minus) => "i64.sub",
+                    (_, BinaryOperator::Multiply) => "i64.mul",
+                    (_, BinaryOperator::Divide) => "call $rt_div",
+                };
+                self.line(instruction);
+                self.set(*temp);
+            }
+            // Null, false, 0, 0.0 and "" are falsy
+            Instruction::Not(temp, value) => {
+                self.get(*value);
+                match self.type_of(*value) {
+                    Type::Null => {
+                        self.line("drop");
+                        self.line("i32.const 1");
+                    }
+                    Type::Bool | Type::Int => self.line("i64.eqz"),
+                    Type::Float => {
+                        self.line("f64.const 0");
+                        self.line("f64.eq");
+                    }
+                    Type::String => {
+                        self.line("i32.wrap_i64");
+                        self.line("i32.load");
+                        self.line("i32.eqz");
+                    }
+                }
+                self.line("i64.extend_i32_u");
+                self.set(*temp);
+            }
+            Instruction::Call(temp, identifier, args) => {
+                for arg in args {
+                    self.get(*arg);
+                }
+                self.line(&format!("call $p_{identifier}"));
+                self.set(*temp);
+            }
+            Instruction::Println(temp, args) => {
+                self.println(args);
+                if self.uses[*temp] > 0 {
+                    self.line("i64.const 0");
+                    self.set(*temp);
+                }
+            }
+        }
+    }
+
+    fn println(&mut self, args: &[Temp]) {
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.print_string(" ");
+            }
+
+            match self.definitions.get(arg) {
+                Some(Instruction::Const(_, Value::String(string))) => self.print_string(string),
+                _ if self.type_of(*arg) == Type::Null => self.print_string("null"),
+                _ => {
+                    self.get(*arg);
+                    let print = match self.type_of(*arg) {
+                        Type::Bool => "call $rt_print_bool",
+                        Type::Float => "call $print_float",
+                        Type::String => "call $rt_print_string",
+                        _ => "call $print_int",
+                    };
+                    self.line(print);
+                }
+            }
+        }
+        self.print_string("\n");
+    }
+
+    fn print_string(&mut self, string: &str) {
+        let address = self.data.string(string) + 4;
+        self.line(&format!("i32.const {address}"));
+        self.line(&format!("i32.const {}", string.len()));
+        self.line("call $print_string");
+    }
+}
+
+// WAT string, anything outside printable ASCII is written as a hex escape
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' => quoted += "\\\"",
+            b'\\' => quoted += "\\\\",
+            b' '..=b'~' => quoted.push(*byte as char),
+            _ => quoted += &format!("\\{byte:02x}"),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmparser::{Parser, Payload, Validator};
+
+    // Compiles like the wasm command does and checks the result is a valid
+    // module
+    fn compile(source: &str) -> Vec<u8> {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        crate::inlining::inline(&mut module);
+        crate::constant_folding::fold(&mut module);
+        let text = generate_code(&module).unwrap();
+        let bytes = wat::parse_str(&text).unwrap_or_else(|error| panic!("{error}\n{text}"));
+        Validator::new().validate_all(&bytes).unwrap_or_else(|error| panic!("{error}\n{text}"));
+        bytes
+    }
+
+    #[test]
+    fn every_type_compiles_to_a_valid_module() {
+        compile(
+            "
+            var scale = 1.5
+            var name = \"fib\"
+            proc describe(label, n, a, b, c, d, e) {
+                println(label, n, n * scale, ! label, ! n, ! scale, ! null, ! true, 10 / n, a + b + c + d + e)
+                return describe(label + \"!\", n - 1, a, b, c, d, e)
+            }
+            proc main() {
+                var half = 1 / 2.0
+                println(name + \" \" + name, half - scale, null, false)
+                describe(name, 3, 1, 2, 3, 4, 5)
+                return 0
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_compile_to_a_valid_module() {
+        compile("println(0.0 / 0.0, 1.0 / 0.0, 0.0 - (1.0 / 0.0))");
+    }
+
+    #[test]
+    fn start_takes_and_returns_nothing() {
+        let bytes = compile("proc main() { return 3 }");
+        let types = Validator::new().validate_all(&bytes).unwrap();
+        let types = types.as_ref();
+        let mut found = false;
+        for payload in Parser::new(0).parse_all(&bytes) {
+            if let Payload::ExportSection(exports) = payload.unwrap() {
+                for export in exports {
+                    let export = export.unwrap();
+                    if export.name == "_start" {
+                        let start = types[types.core_function_at(export.index)].unwrap_func();
+                        assert!(start.params().is_empty() && start.results().is_empty());
+                        found = true;
+                    }
+                }
+            }
+        }
+        assert!(found);
+    }
+}
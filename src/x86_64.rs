@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use crate::ast::{BinaryOperator, Identifier, Type};
+use crate::diagnostic::Diagnostic;
+use crate::ir::{self, Instruction, Module, Temp, Terminator, Value, Variable};
+use crate::value_types::{self, Types};
+
+// Registers for the first six arguments, the rest go on the stack
+const ARGUMENT_REGISTERS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+// Routines the generated code calls, they only use syscalls so the output
+// links without libc
+const RUNTIME: &str = "\
+# Writes %rax to stdout in decimal
+rt_print_int:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $32, %rsp
+    movq %rax, %r8
+    leaq -1(%rbp), %rsi
+    testq %rax, %rax
+    jns 1f
+    negq %rax
+1:
+    movq $10, %r9
+2:
+    xorq %rdx, %rdx
+    divq %r9
+    addb $'0', %dl
+    movb %dl, (%rsi)
+    decq %rsi
+    testq %rax, %rax
+    jnz 2b
+    testq %r8, %r8
+    jns 3f
+    movb $'-', (%rsi)
+    decq %rsi
+3:
+    incq %rsi
+    movq %rbp, %rdx
+    subq %rsi, %rdx
+    movq $1, %rax
+    movq $1, %rdi
+    syscall
+    leave
+    ret
+
+# Writes true or false for %rax
+rt_print_bool:
+    leaq rt_true(%rip), %rsi
+    movq $4, %rdx
+    testq %rax, %rax
+    jnz 1f
+    leaq rt_false(%rip), %rsi
+    movq $5, %rdx
+1:
+    movq $1, %rax
+    movq $1, %rdi
+    syscall
+    ret
+
+# Writes the string %rax points to
+rt_print_string:
+    movq (%rax), %rdx
+    leaq 8(%rax), %rsi
+    movq $1, %rax
+    movq $1, %rdi
+    syscall
+    ret
+
+# %rax = %rax / %rcx, wrapping like i64 in Rust
+rt_div:
+    testq %rcx, %rcx
+    jz rt_division_by_zero
+    cmpq $-1, %rcx
+    jne 1f
+    negq %rax
+    ret
+1:
+    cqto
+    idivq %rcx
+    ret
+
+rt_division_by_zero:
+    movq $1, %rax
+    movq $2, %rdi
+    leaq rt_division_message(%rip), %rsi
+    movq $rt_division_message_length, %rdx
+    syscall
+    movq $1, %rax
+    jmp rt_exit
+
+# Exits with the status in %rax
+rt_exit:
+    movq %rax, %rdi
+    movq $60, %rax
+    syscall
+";
+
+// Where a variable lives, relative to %rbp in the current frame or a global
+// label
+#[derive(Clone)]
+enum Location {
+    Frame(i64),
+    Global(String),
+}
+
+impl Location {
+    fn operand(&self) -> String {
+        match self {
+            Location::Frame(offset) => format!("{offset}(%rbp)"),
+            Location::Global(label) => format!("{label}(%rip)"),
+        }
+    }
+}
+
+// Compiles a program to x86-64 assembly for the GNU assembler, AT&T syntax,
+// for Linux. Build it with `as out.s -o out.o && ld out.o -o out`.
+//
+// Every value is a 64-bit word with a type known when compiling, see
+// value_types. Ints are themselves, true is 1, false and null are 0. Strings
+// point at their length followed by their bytes, and floats at their bits
+// followed by their text as a string, so they print like in Rust. Arithmetic
+// is only done on ints, other operands are rejected.
+//
+// Procs follow the System V calling convention. Every IR temp has a slot in
+// the frame, instructions load their operands into %rax and %rcx and store
+// the result back, so the stack stays 16 byte aligned at every call. _start
+// runs the top level code, calls main and exits with what it returns if that
+// is an int, otherwise with 0.
+pub fn generate_code(module: &Module) -> Result<String, Vec<Diagnostic>> {
+    let types = value_types::infer(module, "x86-64", |_, left, right| match (left, right) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        _ => None,
+    })?;
+
+    let globals: HashMap<Variable, Location> = module
+        .globals
+        .iter()
+        .map(|name| (Variable::Global(name.clone()), Location::Global(format!("v_{name}"))))
+        .collect();
+
+    let mut data = Data::default();
+    let mut text = String::new();
+
+    for proc in &module.procs {
+        let mut scope = globals.clone();
+
+        // Register arguments are stored in the frame next to the locals,
+        // stack arguments are above the return address
+        let mut slots = 0;
+        let mut slot = || {
+            slots += 1;
+            Location::Frame(-8 * slots)
+        };
+        let mut spills = vec![];
+        for (index, arg) in proc.params.iter().enumerate() {
+            let location = match ARGUMENT_REGISTERS.get(index) {
+                Some(register) => {
+                    let location = slot();
+                    spills.push(format!("movq {register}, {}", location.operand()));
+                    location
+                }
+                None => Location::Frame(16 + 8 * (index - ARGUMENT_REGISTERS.len()) as i64),
+            };
+            scope.insert(Variable::Local(arg.clone()), location);
+        }
+        for name in &proc.locals {
+            scope.insert(Variable::Local(name.clone()), slot());
+        }
+
+        let label = format!("p_{}", proc.name);
+        let slots = slots as usize;
+        let mut function = Function::new(proc, &types, &scope, &mut data, &label, slots, "leave\n    ret");
+        function.function(proc);
+        let body = function.body;
+
+        text += &format!("\n{label}:\n");
+        text += "    pushq %rbp\n";
+        text += "    movq %rsp, %rbp\n";
+        text += &reserve(slots + proc.temps);
+        for spill in spills {
+            text += &format!("    {spill}\n");
+        }
+        text += &body;
+    }
+
+    let mut scope = globals.clone();
+    for (slot, name) in module.entry.locals.iter().enumerate() {
+        scope.insert(Variable::Local(name.clone()), Location::Frame(-8 * (slot as i64 + 1)));
+    }
+    let slots = module.entry.locals.len();
+    let mut function = Function::new(&module.entry, &types, &scope, &mut data, "_start", slots, "jmp rt_exit");
+    function.function(&module.entry);
+    let start = function.body;
+
+    let mut output = String::new();
+    output += "    .section .rodata\n";
+    output += &data.rodata;
+    output += "rt_division_message:\n";
+    output += "    .ascii \"Runtime error: Division by zero\\n\"\n";
+    output += "    .set rt_division_message_length, . - rt_division_message\n";
+    output += "rt_true:\n    .ascii \"true\"\n";
+    output += "rt_false:\n    .ascii \"false\"\n";
+    output += "\n    .bss\n";
+    output += "    .align 8\n";
+    for name in &module.globals {
+        output += &format!("v_{name}:\n    .zero 8\n");
+    }
+    output += "\n    .text\n";
+    output += "    .globl _start\n";
+    output += "_start:\n";
+    // The stack is 16 byte aligned on entry, with no return address
+    output += "    movq %rsp, %rbp\n";
+    output += &reserve(slots + module.entry.temps);
+    output += &start;
+    output += &text;
+    output += "\n";
+    output += RUNTIME;
+    Ok(output)
+}
+
+// Makes room for the frame's slots, keeping %rsp 16 byte aligned
+fn reserve(slots: usize) -> String {
+    if slots == 0 {
+        return String::new();
+    }
+    format!("    subq ${}, %rsp\n", (slots + slots % 2) * 8)
+}
+
+// Literals, numbered in the order they are first used
+#[derive(Default)]
+struct Data {
+    rodata: String,
+    strings: HashMap<String, String>,
+    floats: usize,
+}
+
+impl Data {
+    fn string(&mut self, string: &str) -> String {
+        if let Some(label) = self.strings.get(string) {
+            return label.clone();
+        }
+
+        let label = format!("s{}", self.strings.len());
+        self.rodata += &format!("    .align 8\n{label}:\n    .quad {}\n    .ascii {}\n", string.len(), quote(string));
+        self.strings.insert(string.to_string(), label.clone());
+        label
+    }
+
+    fn float(&mut self, float: f64) -> String {
+        let label = format!("f{}", self.floats);
+        self.floats += 1;
+        let text = float.to_string();
+        self.rodata += &format!(
+            "    .align 8\n{label}:\n    .quad {:#x}\n    .quad {}\n    .ascii {}\n",
+            float.to_bits(),
+            text.len(),
+            quote(&text)
+        );
+        label
+    }
+}
+
+struct Function<'a> {
+    body: String,
+    function: &'a ir::Function,
+    types: &'a Types,
+    scope: &'a HashMap<Variable, Location>,
+    definitions: HashMap<Temp, &'a Instruction>,
+    data: &'a mut Data,
+    label: &'a str, // Block labels are prefixed with it
+    temps: i64, // Slots used by variables, temps come after them
+    ret: &'static str, // Returns the value in %rax
+}
+
+impl<'a> Function<'a> {
+    fn new(
+        function: &'a ir::Function,
+        types: &'a Types,
+        scope: &'a HashMap<Variable, Location>,
+        data: &'a mut Data,
+        label: &'a str,
+        slots: usize,
+        ret: &'static str,
+    ) -> Self {
+        Function {
+            body: String::new(),
+            function,
+            types,
+            scope,
+            definitions: function.definitions(),
+            data,
+            label,
+            temps: slots as i64,
+            ret,
+        }
+    }
+
+    fn line(&mut self, line: String) {
+        self.body += "    ";
+        self.body += &line;
+        self.body += "\n";
+    }
+
+    fn slot(&self, temp: Temp) -> String {
+        format!("{}(%rbp)", -8 * (self.temps + 1 + temp as i64))
+    }
+
+    // Constants are materialized where they are used
+    fn load(&mut self, temp: Temp, register: &str) {
+        let line = match self.definitions.get(&temp) {
+            Some(Instruction::Const(_, Value::Null | Value::Bool(false))) => format!("xorq {register}, {register}"),
+            Some(Instruction::Const(_, Value::Bool(true))) => format!("movq $1, {register}"),
+            Some(Instruction::Const(_, Value::Int(int))) => format!("movabsq ${int}, {register}"),
+            Some(Instruction::Const(_, Value::Float(float))) => {
+                format!("leaq {}(%rip), {register}", self.data.float(*float))
+            }
+            Some(Instruction::Const(_, Value::String(string))) => {
+                format!("leaq {}(%rip), {register}", self.data.string(string))
+            }
+            _ => format!("movq {}, {register}", self.slot(temp)),
+        };
+        self.line(line);
+    }
+
+    fn type_of(&self, temp: Temp) -> Type {
+        self.types.temp(self.function, temp)
+    }
+
+    fn store(&mut self, temp: Temp) {
+        self.line(format!("movq %rax, {}", self.slot(temp)));
+    }
+
+    fn function(&mut self, function: &ir::Function) {
+        let targets = function.targets();
+        for (id, block) in function.blocks.iter().enumerate() {
+            if targets.contains(&id) {
+                self.body += &format!("{}.b{id}:\n", self.label);
+            }
+
+            for instruction in &block.instructions {
+                self.instruction(instruction);
+            }
+
+            match block.terminator {
+                // Only ints are exit statuses
+                Terminator::Return(_) if function.name.is_empty() && self.types.returns(function) != Type::Int => {
+                    self.line("xorq %rax, %rax".to_string());
+                    self.line(self.ret.to_string());
+                }
+                Terminator::Return(temp) => {
+                    self.load(temp, "%rax");
+                    self.line(self.ret.to_string());
+                }
+                Terminator::Jump(target) => self.line(format!("jmp {}.b{target}", self.label)),
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Const(..) => {}
+            Instruction::Load(temp, variable) => {
+                self.line(format!("movq {}, %rax", self.scope[variable].operand()));
+                self.store(*temp);
+            }
+            Instruction::Store(variable, temp) => {
+                self.load(*temp, "%rax");
+                self.line(format!("movq %rax, {}", self.scope[variable].operand()));
+            }
+            Instruction::Binary(temp, operator, left, right) => {
+                self.load(*left, "%rax");
+                self.load(*right, "%rcx");
+                let instruction = match operator {
+                    BinaryOperator::Plus => "addq %rcx, %rax",
+                    BinaryOperator::Minus => "subq %rcx, %rax",
+                    BinaryOperator::Multiply => "imulq %rcx, %rax",
+                    BinaryOperator::Divide => "call rt_div",
+                };
+                self.line(instruction.to_string());
+                self.store(*temp);
+            }
+            // Null, false, 0, 0.0 and "" are falsy
+            Instruction::Not(temp, value) => {
+                self.load(*value, "%rax");
+                match self.type_of(*value) {
+                    Type::Null => self.line("xorq %rax, %rax".to_string()),
+                    Type::Bool | Type::Int => {}
+                    Type::String => self.line("movq (%rax), %rax".to_string()),
+                    // Without the sign bit, so -0.0 is 0
+                    Type::Float => {
+                        self.line("movq (%rax), %rax".to_string());
+                        self.line("shlq $1, %rax".to_string());
+                    }
+                }
+                self.line("testq %rax, %rax".to_string());
+                self.line("sete %al".to_string());
+                self.line("movzbq %al, %rax".to_string());
+                self.store(*temp);
+            }
+            Instruction::Call(temp, identifier, args) => {
+                self.call(identifier, args);
+                self.store(*temp);
+            }
+            Instruction::Println(temp, args) => {
+                self.println(args);
+                self.line("xorq %rax, %rax".to_string());
+                self.store(*temp);
+            }
+        }
+    }
+
+    // Stack arguments are pushed last first so the first one is lowest, then
+    // the ones that go in registers are loaded
+    fn call(&mut self, identifier: &Identifier, args: &[Temp]) {
+        let on_stack = args.len().saturating_sub(ARGUMENT_REGISTERS.len());
+        let padding = on_stack % 2;
+        if padding == 1 {
+            self.line("subq $8, %rsp".to_string());
+        }
+        for arg in args.iter().skip(ARGUMENT_REGISTERS.len()).rev() {
+            self.load(*arg, "%rax");
+            self.line("pushq %rax".to_string());
+        }
+        for (arg, register) in args.iter().zip(ARGUMENT_REGISTERS) {
+            self.load(*arg, register);
+        }
+
+        self.line(format!("call p_{identifier}"));
+        let words = on_stack + padding;
+        if words > 0 {
+            self.line(format!("addq ${}, %rsp", words * 8));
+        }
+    }
+
+    fn println(&mut self, args: &[Temp]) {
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.write(" ");
+            }
+
+            match self.definitions.get(arg) {
+                Some(Instruction::Const(_, Value::String(string))) => self.write(string),
+                Some(Instruction::Const(_, Value::Float(float))) => self.write(&float.to_string()),
+                _ => match self.type_of(*arg) {
+                    Type::Null => self.write("null"),
+                    Type::Bool => {
+                        self.load(*arg, "%rax");
+                        self.line("call rt_print_bool".to_string());
+                    }
+                    Type::Int => {
+                        self.load(*arg, "%rax");
+                        self.line("call rt_print_int".to_string());
+                    }
+                    Type::String => {
+                        self.load(*arg, "%rax");
+                        self.line("call rt_print_string".to_string());
+                    }
+                    Type::Float => {
+                        self.load(*arg, "%rax");
+                        self.line("addq $8, %rax".to_string());
+                        self.line("call rt_print_string".to_string());
+                    }
+                },
+            }
+        }
+        self.write("\n");
+    }
+
+    fn write(&mut self, string: &str) {
+        let label = self.data.string(string);
+        self.line("movq $1, %rax".to_string());
+        self.line("movq $1, %rdi".to_string());
+        self.line(format!("leaq {label}+8(%rip), %rsi"));
+        self.line(format!("movq ${}, %rdx", string.len()));
+        self.line("syscall".to_string());
+    }
+}
+
+// GNU assembler string, anything outside printable ASCII is written as an
+// octal escape
+fn quote(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' => quoted += "\\\"",
+            b'\\' => quoted += "\\\\",
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted += &format!("\\{byte:03o}"),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+        let mut module = Module::from_ast(&crate::parse(source.to_string())).unwrap();
+        crate::dead_code::remove_unreachable_blocks(&mut module);
+        generate_code(&module)
+    }
+
+    #[test]
+    fn rejects_values_of_two_types() {
+        let diagnostics =
+            generate_source("proc main() {\n    var x = 1\n    x = \"a\"\n    return x\n}\nprintln(1 + 2.5)").unwrap_err();
+        let messages: Vec<String> = diagnostics
+            .iter()
+            .map(|diagnostic| format!("{} at {}", diagnostic, diagnostic.span.unwrap()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "error: the x86-64 backend cannot apply + to int and float at the top level at 6:1",
+                "error: x holds int and string in proc main, the x86-64 backend needs one type for it at 3:5",
+            ]
+        );
+    }
+
+    // The 7th and 8th args are pushed last first, so the callee finds them
+    // above its saved %rbp and return address. An odd number of stack args
+    // gets a padding word to keep the call aligned.
+    #[test]
+    fn passes_args_after_the_sixth_on_the_stack() {
+        let code = generate_source(
+            "
+            proc pick(a, b, c, d, e, f, g, h) {
+                return g - h
+            }
+            proc seventh(a, b, c, d, e, f, g) {
+                return g
+            }
+            var x = pick(1, 2, 3, 4, 5, 6, 70, 7)
+            return x + seventh(1, 2, 3, 4, 5, 6, 7)
+            ",
+        )
+        .unwrap();
+        let section = |start: &str, end: &str| {
+            let start = code.find(start).unwrap();
+            code[start..start + code[start..].find(end).unwrap() + end.len()].to_string()
+        };
+        assert_eq!(
+            section("p_pick:", "ret\n"),
+            "p_pick:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $96, %rsp
+    movq %rdi, -8(%rbp)
+    movq %rsi, -16(%rbp)
+    movq %rdx, -24(%rbp)
+    movq %rcx, -32(%rbp)
+    movq %r8, -40(%rbp)
+    movq %r9, -48(%rbp)
+    movq 16(%rbp), %rax
+    movq %rax, -56(%rbp)
+    movq 24(%rbp), %rax
+    movq %rax, -64(%rbp)
+    movq -56(%rbp), %rax
+    movq -64(%rbp), %rcx
+    subq %rcx, %rax
+    movq %rax, -72(%rbp)
+    movq -72(%rbp), %rax
+    leave
+    ret
+"
+        );
+        assert_eq!(
+            section("    movabsq $7", "call p_pick\n    addq $16, %rsp\n"),
+            "    movabsq $7, %rax
+    pushq %rax
+    movabsq $70, %rax
+    pushq %rax
+    movabsq $1, %rdi
+    movabsq $2, %rsi
+    movabsq $3, %rdx
+    movabsq $4, %rcx
+    movabsq $5, %r8
+    movabsq $6, %r9
+    call p_pick
+    addq $16, %rsp
+"
+        );
+        assert_eq!(
+            section("    subq $8, %rsp", "call p_seventh\n    addq $16, %rsp\n"),
+            "    subq $8, %rsp
+    movabsq $7, %rax
+    pushq %rax
+    movabsq $1, %rdi
+    movabsq $2, %rsi
+    movabsq $3, %rdx
+    movabsq $4, %rcx
+    movabsq $5, %r8
+    movabsq $6, %r9
+    call p_seventh
+    addq $16, %rsp
+"
+        );
+        assert!(section("p_seventh:", "ret\n").contains("    movq 16(%rbp), %rax\n"));
+    }
+}